@@ -0,0 +1,122 @@
+//! Accounts and their logins. Passwords go through Argon2, slow on purpose:
+//! call register and login off the async workers.
+
+use crate::database::{unix_time, Account, GameDatabase};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hyper::header::COOKIE;
+use hyper::HeaderMap;
+use std::fmt;
+use std::time::Duration;
+
+pub const LOGIN_COOKIE: &str = "session";
+/// Start of the random names given to guests, which accounts may not take.
+pub const GUEST_PREFIX: &str = "guest-";
+const LOGIN_LIFETIME: Duration = Duration::from_secs(30 * 24 * 3600);
+const NAME_LENGTH: std::ops::RangeInclusive<usize> = 3..=20;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 128;
+/// Checked against when the name is unknown, so that a login takes as long
+/// whether the account exists or not. Made with the default Argon2 parameters.
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$BwcHBwcHBwcHBwcHBwcHBw$nHBw66xF3TJlg8KIg+3XQB/VuYgDEd/RgVU+NZT7ibQ";
+
+#[derive(Debug)]
+pub enum AccountError {
+    InvalidName,
+    /// The name could be mistaken for a guest.
+    ReservedName,
+    InvalidPassword,
+    NameTaken,
+    /// Unknown name or wrong password, on purpose not told apart.
+    WrongCredentials,
+    Storage(String),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountError::InvalidName => write!(
+                f, "names have {} to {} letters, digits, '-' or '_'", NAME_LENGTH.start(), NAME_LENGTH.end()
+            ),
+            AccountError::ReservedName => write!(f, "names starting with {:?} are kept for guests", GUEST_PREFIX),
+            AccountError::InvalidPassword => write!(
+                f, "passwords have {} to {} characters", MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+            ),
+            AccountError::NameTaken => write!(f, "this name is already taken"),
+            AccountError::WrongCredentials => write!(f, "wrong name or password"),
+            AccountError::Storage(e) => write!(f, "account storage failed: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AccountError {
+    fn from(e: rusqlite::Error) -> Self {
+        AccountError::Storage(e.to_string())
+    }
+}
+
+/// Creates the account and logs it in, answering with the login token.
+pub fn register(database: &GameDatabase, name: &str, password: &str) -> Result<(Account, String), AccountError> {
+    if !NAME_LENGTH.contains(&name.chars().count())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(AccountError::InvalidName);
+    }
+    if name.to_ascii_lowercase().starts_with(GUEST_PREFIX) {
+        return Err(AccountError::ReservedName);
+    }
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&password.chars().count()) {
+        return Err(AccountError::InvalidPassword);
+    }
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| AccountError::Storage(e.to_string()))?;
+    let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map_err(|e| AccountError::Storage(e.to_string()))?
+        .to_string();
+    let account = database.create_account(name, &password_hash)?.ok_or(AccountError::NameTaken)?;
+    let token = start_login(database, &account)?;
+    Ok((account, token))
+}
+
+/// Checks the password and logs the account in, answering with the login token.
+pub fn login(database: &GameDatabase, name: &str, password: &str) -> Result<(Account, String), AccountError> {
+    let Some((account, password_hash)) = database.account_by_name(name)? else {
+        // as slow as a wrong password, unknown names must not answer faster
+        verify_password(password, DUMMY_PASSWORD_HASH)?;
+        return Err(AccountError::WrongCredentials);
+    };
+    if !verify_password(password, &password_hash)? {
+        return Err(AccountError::WrongCredentials);
+    }
+    let token = start_login(database, &account)?;
+    Ok((account, token))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> Result<bool, AccountError> {
+    let parsed = PasswordHash::new(password_hash).map_err(|e| AccountError::Storage(e.to_string()))?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+fn start_login(database: &GameDatabase, account: &Account) -> Result<String, AccountError> {
+    let token: String = rand::random::<[u8; 32]>().iter().map(|byte| format!("{:02x}", byte)).collect();
+    database.create_login(&token, account.id, unix_time() + LOGIN_LIFETIME.as_secs())?;
+    println!("Account {} logged in", account.name);
+    Ok(token)
+}
+
+/// The Set-Cookie value carrying a login token, an empty token clears it.
+/// Secure cookies are only sent back over https.
+pub fn login_cookie(token: &str, secure: bool) -> String {
+    let max_age = if token.is_empty() { 0 } else { LOGIN_LIFETIME.as_secs() };
+    let secure = if secure { "; Secure" } else { "" };
+    format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}", LOGIN_COOKIE, token, max_age, secure)
+}
+
+/// The login token sent back by the browser, if any.
+pub fn login_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get_all(COOKIE).iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|&(name, _)| name == LOGIN_COOKIE)
+        .map(|(_, token)| token)
+}
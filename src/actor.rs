@@ -0,0 +1,212 @@
+use crate::board::notation::Notation;
+use crate::board::CellOwner;
+use crate::message::{ClientMessage, GameMessageFactory, ServerMessage};
+use crate::registry::SessionEvent;
+use crate::session::{GameSession, GameSessionPhase, Player, SessionStatus};
+use crate::variant::VariantBoard;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::StreamExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+const CLOCK_TICK: Duration = Duration::from_secs(1);
+const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+type Sink = Arc<UnboundedSender<ServerMessage>>;
+
+/// What the task owning a session can be asked to do.
+pub enum SessionCommand {
+    /// Seats a second player in a lobby, answers false once the lobby is gone.
+    Join { sender: Sink, player: Player, reply: oneshot::Sender<bool> },
+    Input { player: CellOwner, message: ClientMessage },
+    /// The socket of a player dropped: during a game the seat is kept for a while.
+    Leave { player: CellOwner, connection_id: u32 },
+    /// The player was disconnected for sending junk: no seat is kept.
+    Kick { player: CellOwner },
+    /// Answers the seat the token belongs to and the id of the new connection.
+    Resume { token: String, sender: Sink, reply: oneshot::Sender<Option<(CellOwner, u32)>> },
+    /// Answers false once the session is closed.
+    Spectate { sender: Sink, reply: oneshot::Sender<bool> },
+    SpectatorState { sender: Sink },
+    Unspectate { sender: Sink },
+    /// Moves the player waiting in this lobby to another lobby, as its PlayerB.
+    HandOver { to: SessionHandle },
+    Notation { reply: oneshot::Sender<Notation> },
+    /// Phase, player to move and board, for the computer player to think on.
+    Snapshot { reply: oneshot::Sender<(GameSessionPhase, CellOwner, VariantBoard)> },
+}
+
+/// Way in to a session task: commands go through a channel and the status
+/// published after each of them can be read without waiting.
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub id: u64,
+    commands: UnboundedSender<SessionCommand>,
+    status: watch::Receiver<SessionStatus>,
+}
+
+impl SessionHandle {
+    pub fn status(&self) -> SessionStatus {
+        self.status.borrow().clone()
+    }
+
+    /// False once the session task is gone.
+    pub fn send(&self, command: SessionCommand) -> bool {
+        self.commands.unbounded_send(command).is_ok()
+    }
+
+    /// Sends a command carrying a reply channel and waits for the answer,
+    /// None if the session task is gone.
+    pub async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> SessionCommand) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        if !self.send(command(reply)) {
+            return None;
+        }
+        answer.await.ok()
+    }
+
+    /// Resolves once the session task stopped, which only happens early if it panicked.
+    pub async fn stopped(&self) {
+        let mut status = self.status.clone();
+        while status.changed().await.is_ok() {}
+    }
+}
+
+/// Starts the task owning the session. Closed sessions and their finished
+/// games are reported on events, closing also when the task panics.
+pub fn spawn_session(
+    mut session: GameSession,
+    game_message_factory: Arc<GameMessageFactory>,
+    events: UnboundedSender<SessionEvent>,
+) -> SessionHandle {
+    let id = session.id;
+    session.report_to(events.clone());
+    let (commands, receiver) = unbounded();
+    let (status_sender, status) = watch::channel(session.status());
+    let task = tokio::spawn(run_session(session, receiver, status_sender, game_message_factory));
+    tokio::spawn(async move {
+        if task.await.is_err() {
+            println!("Session {} crashed", id);
+            events.unbounded_send(SessionEvent::Closed(id)).unwrap_or_else(|e| println!("Could not report crashed session: {}", e));
+        }
+    });
+    SessionHandle { id, commands, status }
+}
+
+/// Runs until every handle is dropped.
+async fn run_session(
+    mut session: GameSession,
+    mut commands: UnboundedReceiver<SessionCommand>,
+    status: watch::Sender<SessionStatus>,
+    game_message_factory: Arc<GameMessageFactory>,
+) {
+    let timed = session.time_control().is_some();
+    let mut clock = tokio::time::interval(CLOCK_TICK);
+    // seats kept for a reconnection: deadline, seat and the connection that dropped
+    let mut grace: Vec<(Instant, CellOwner, u32)> = Vec::new();
+    // the lobby whose waiting player was moved elsewhere only forwards their input
+    let mut moved_to: Option<SessionHandle> = None;
+    loop {
+        let next_expiry = grace.iter().map(|&(deadline, _, _)| deadline).min();
+        tokio::select! {
+            command = commands.next() => match command {
+                Some(command) => match &moved_to {
+                    Some(host) => forward(host, command),
+                    None => handle_command(&mut session, command, &mut grace, &mut moved_to, &game_message_factory).await,
+                },
+                None => break,
+            },
+            _ = clock.tick(), if timed && session.phase != GameSessionPhase::CLOSED => {
+                session.tick_clock(&game_message_factory);
+            }
+            _ = tokio::time::sleep_until(next_expiry.unwrap_or_else(Instant::now).into()), if next_expiry.is_some() => {
+                let now = Instant::now();
+                grace.retain(|&(deadline, player, connection_id)| {
+                    if deadline > now {
+                        return true;
+                    }
+                    session.expire_seat(player, connection_id, &game_message_factory);
+                    false
+                });
+            }
+        }
+        status.send_replace(session.status());
+    }
+}
+
+async fn handle_command(
+    session: &mut GameSession,
+    command: SessionCommand,
+    grace: &mut Vec<(Instant, CellOwner, u32)>,
+    moved_to: &mut Option<SessionHandle>,
+    game_message_factory: &GameMessageFactory,
+) {
+    match command {
+        SessionCommand::Join { sender, player, reply } => {
+            let accepted = session.phase == GameSessionPhase::LOBBY;
+            if accepted {
+                session.join(sender, player, game_message_factory);
+            }
+            // the requester may be gone already, there is nothing to undo then
+            reply.send(accepted).ok();
+        }
+        SessionCommand::Input { player, message } => session.process_player_input(player, message, game_message_factory),
+        SessionCommand::Leave { player, connection_id } => {
+            if session.disconnect(player, connection_id, game_message_factory) {
+                grace.push((Instant::now() + RECONNECT_GRACE_PERIOD, player, connection_id));
+            }
+        }
+        SessionCommand::Kick { player } => session.close_session(player, game_message_factory),
+        SessionCommand::Resume { token, sender, reply } => {
+            let resumed = match session.seat_of_token(&token) {
+                Some(player) if session.phase != GameSessionPhase::CLOSED => {
+                    session.resume(player, sender, game_message_factory);
+                    Some((player, session.connection_id(player)))
+                }
+                _ => None,
+            };
+            reply.send(resumed).ok();
+        }
+        SessionCommand::Spectate { sender, reply } => {
+            let open = session.phase != GameSessionPhase::CLOSED;
+            if open {
+                session.add_spectator(sender, game_message_factory);
+            }
+            reply.send(open).ok();
+        }
+        SessionCommand::SpectatorState { sender } => session.send_state(&sender, None),
+        SessionCommand::Unspectate { sender } => session.remove_spectator(&sender),
+        SessionCommand::HandOver { to } => {
+            if session.status().waiting_rating().is_none() {
+                return;
+            }
+            let (sender, player) = session.waiting_player();
+            // the host is always an older lobby, that never waits on this one
+            if to.request(|reply| SessionCommand::Join { sender, player, reply }).await == Some(true) {
+                session.hand_over();
+                *moved_to = Some(to);
+            }
+        }
+        SessionCommand::Notation { reply } => {
+            reply.send(session.notation()).ok();
+        }
+        SessionCommand::Snapshot { reply } => {
+            reply.send((session.phase, session.turn, session.board.clone())).ok();
+        }
+    }
+}
+
+/// Input of a player moved by the matchmaking goes to their new seat.
+fn forward(host: &SessionHandle, command: SessionCommand) {
+    let forwarded = match command {
+        SessionCommand::Input { message, .. } => SessionCommand::Input { player: CellOwner::PlayerB, message },
+        SessionCommand::Leave { connection_id, .. } => SessionCommand::Leave { player: CellOwner::PlayerB, connection_id },
+        SessionCommand::Kick { .. } => SessionCommand::Kick { player: CellOwner::PlayerB },
+        // anything else was meant for the closed lobby and is answered by
+        // dropping its reply channel
+        _ => return,
+    };
+    host.send(forwarded);
+}
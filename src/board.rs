@@ -1,3 +1,11 @@
+pub use bitmask::Bitmask;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+mod bitmask;
+pub mod notation;
+
 #[derive(PartialEq, Clone, Copy)]
 pub enum CellOwner {
     None,
@@ -17,93 +25,294 @@ impl CellOwner {
     }
 }
 
-pub struct GameBoard {
-    cells: [CellOwner; 9],
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct BoardSize {
+    pub width: usize,
+    pub height: usize,
+    pub win_length: usize,
 }
 
-impl GameBoard {
-    pub fn new() -> GameBoard {
-        GameBoard { cells: [CellOwner::None; 9] }
-    }
+impl BoardSize {
+    pub const CLASSIC: BoardSize = BoardSize { width: 3, height: 3, win_length: 3 };
+    const MIN_SIDE: usize = 3;
+    const MAX_SIDE: usize = 15;
 
-    pub fn check_winner(&self) -> CellOwner {
-        self.check_board_health();
-        let checks: [CellOwner; 4] = [
-            self.line_check(|i| i / 3), //rows
-            self.line_check(|i| i % 3), //columns
-            self.diagonal_check(|i| i % 4 == 0),
-            self.diagonal_check(|i| i != 0 && i != 8 && i % 2 == 0)
-        ];
-        let winner = checks.iter()
-            .find(|&&x| x == CellOwner::PlayerA || x == CellOwner::PlayerB);
-        match winner {
-            Some(&owner) => owner,
-            None => self.cells.iter().find(|&&x| x == CellOwner::None)
-                .map(|x| *x)
-                .unwrap_or(CellOwner::Tie)
+    pub fn new(width: usize, height: usize, win_length: usize) -> Option<BoardSize> {
+        let sides = Self::MIN_SIDE..=Self::MAX_SIDE;
+        if sides.contains(&width) && sides.contains(&height)
+            && win_length >= Self::MIN_SIDE && win_length <= width.max(height) {
+            Some(BoardSize { width, height, win_length })
+        } else {
+            None
         }
     }
 
-    pub fn update_cell(&mut self, index: usize, owner: CellOwner) -> bool {
-        if self.cells[index] == CellOwner::None {
-            self.cells[index] = owner;
-            true
-        } else {
-            false
-        }
+    pub fn cell_count(&self) -> usize {
+        self.width * self.height
     }
+}
 
-    fn check_board_health(&self) {
-        let mut a_count: i32 = 0;
-        let mut b_count: i32 = 0;
-        for cell in self.cells {
-            if cell == CellOwner::PlayerA {
-                a_count += 1;
-            } else if cell == CellOwner::PlayerB {
-                b_count += 1;
-            }
+impl fmt::Display for BoardSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.width, self.height, self.win_length)
+    }
+}
+
+/// Winner of a board and the cells of every line they completed, as moves.
+pub struct Outcome<M> {
+    pub winner: CellOwner,
+    pub lines: Vec<Vec<M>>,
+}
+
+/// Cells of every winning line on a board of this size, computed once per
+/// size and shared by all the boards using it.
+fn line_masks(size: BoardSize) -> &'static [Bitmask] {
+    if size == BoardSize::CLASSIC {
+        // every small board of an ultimate game is classic: no lock for them
+        return classic_lines();
+    }
+    static LINES: OnceLock<Mutex<HashMap<BoardSize, &'static [Bitmask]>>> = OnceLock::new();
+    let mut lines = LINES.get_or_init(Default::default).lock().unwrap();
+    lines.entry(size).or_insert_with(|| Vec::leak(compute_lines(size)))
+}
+
+/// The 8 lines of the classic board, also those of the ultimate big board.
+pub fn classic_lines() -> &'static [Bitmask] {
+    static LINES: OnceLock<[Bitmask; 8]> = OnceLock::new();
+    LINES.get_or_init(|| compute_lines(BoardSize::CLASSIC).try_into().unwrap())
+}
+
+/// Owner of the first line whose cells all belong to one player.
+pub fn line_owner(lines: &[Bitmask], player_a: &Bitmask, player_b: &Bitmask) -> CellOwner {
+    for line in lines {
+        if line.is_subset(player_a) {
+            return CellOwner::PlayerA;
         }
-        if (a_count - b_count).abs() >= 2 {
-            panic!("Session corrupted: to many moves from one player")
+        if line.is_subset(player_b) {
+            return CellOwner::PlayerB;
         }
     }
+    CellOwner::None
+}
+
+/// Lines whose cells are all among the given ones, each as its cell indexes.
+pub fn lines_within(lines: &[Bitmask], cells: &Bitmask) -> Vec<Vec<usize>> {
+    lines.iter()
+        .filter(|line| line.is_subset(cells))
+        .map(|line| line.iter().collect())
+        .collect()
+}
 
-    fn diagonal_check<F: Fn(usize) -> bool>(&self, filter: F) -> CellOwner {
-        let mut a_count: i32 = 0;
-        let mut b_count: i32 = 0;
-        for (index, &owner) in self.cells.iter().enumerate() {
-            if filter(index) {
-                match owner {
-                    CellOwner::PlayerA => a_count += 1,
-                    CellOwner::PlayerB => b_count += 1,
-                    _ => ()
+fn compute_lines(size: BoardSize) -> Vec<Bitmask> {
+    // right, down, down-right and down-left: every line is found from its first cell
+    let directions: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (-1, 1)];
+    let (width, height, length) = (size.width as isize, size.height as isize, size.win_length as isize);
+    let mut masks = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            for (dx, dy) in directions {
+                let (end_x, end_y) = (x + dx * (length - 1), y + dy * (length - 1));
+                if end_x < 0 || end_x >= width || end_y >= height {
+                    continue;
                 }
+                let mut mask = Bitmask::default();
+                for step in 0..length {
+                    mask.insert(((y + dy * step) * width + x + dx * step) as usize);
+                }
+                masks.push(mask);
             }
         }
-        if a_count == 3 {
+    }
+    masks
+}
+
+/// Cells are kept as one bitmask per player, plus the cells taken by either
+/// of them or tied, so that a line is won when its mask is a subset of the
+/// player's.
+#[derive(Clone)]
+pub struct GameBoard {
+    size: BoardSize,
+    player_a: Bitmask,
+    player_b: Bitmask,
+    taken: Bitmask,
+    cells: Bitmask,
+    lines: &'static [Bitmask],
+}
+
+impl GameBoard {
+    pub fn new(size: BoardSize) -> GameBoard {
+        assert!(size.cell_count() <= Bitmask::CAPACITY, "board too large for its bitmasks");
+        GameBoard {
+            size,
+            player_a: Bitmask::default(),
+            player_b: Bitmask::default(),
+            taken: Bitmask::default(),
+            cells: Bitmask::first(size.cell_count()),
+            lines: line_masks(size),
+        }
+    }
+
+    pub fn size(&self) -> BoardSize {
+        self.size
+    }
+
+    /// The owner of a completed line, Tie once the board is full. Small
+    /// boards of ultimate games are not played alternately: the number of
+    /// moves of each player is not checked.
+    pub fn check_winner(&self) -> CellOwner {
+        match line_owner(self.lines, &self.player_a, &self.player_b) {
+            CellOwner::None if self.taken == self.cells => CellOwner::Tie,
+            owner => owner,
+        }
+    }
+
+    /// Same as check_winner, along with the lines that decided the game.
+    pub fn check_outcome(&self) -> Outcome<usize> {
+        let winner = self.check_winner();
+        Outcome { winner, lines: self.completed_lines(winner) }
+    }
+
+    /// Lines completed by the owner, each as its cell indexes.
+    pub fn completed_lines(&self, owner: CellOwner) -> Vec<Vec<usize>> {
+        let cells = match owner {
+            CellOwner::PlayerA => self.player_a,
+            CellOwner::PlayerB => self.player_b,
+            _ => return Vec::new(),
+        };
+        lines_within(self.lines, &cells)
+    }
+
+    pub fn owner(&self, index: usize) -> CellOwner {
+        if self.player_a.contains(index) {
             CellOwner::PlayerA
-        } else if b_count == 3 {
+        } else if self.player_b.contains(index) {
             CellOwner::PlayerB
+        } else if self.taken.contains(index) {
+            CellOwner::Tie
         } else {
             CellOwner::None
         }
     }
 
-    fn line_check<F: Fn(usize) -> usize>(&self, transform: F) -> CellOwner {
-        let mut a_counts = [0, 0, 0];
-        let mut b_counts = [0, 0, 0];
-        for (index, &owner) in self.cells.iter().enumerate() {
-            let index = transform(index); // runtime upperbound of this operation is 2
-            match owner {
-                CellOwner::PlayerA => a_counts[index] += 1,
-                CellOwner::PlayerB => b_counts[index] += 1,
-                _ => ()
-            }
+    /// False if the cell is already taken or out of the board.
+    pub fn update_cell(&mut self, index: usize, owner: CellOwner) -> bool {
+        if index >= self.size.cell_count() || self.taken.contains(index) {
+            return false;
         }
-        if a_counts.iter().any(|&x| x == 3) {
-            CellOwner::PlayerA
-        } else if b_counts.iter().any(|&x| x == 3) {
-            CellOwner::PlayerB
-        } else { CellOwner::None }
+        match owner {
+            CellOwner::PlayerA => self.player_a.insert(index),
+            CellOwner::PlayerB => self.player_b.insert(index),
+            CellOwner::Tie => (),
+            CellOwner::None => return true,
+        }
+        self.taken.insert(index);
+        true
+    }
+
+    /// Frees a taken cell, false if it was not taken. The order of the
+    /// moves is up to the caller: boards are cloned for every search node.
+    pub fn undo_move(&mut self, index: usize) -> bool {
+        if index >= self.size.cell_count() || !self.taken.contains(index) {
+            return false;
+        }
+        self.player_a.remove(index);
+        self.player_b.remove(index);
+        self.taken.remove(index);
+        true
+    }
+
+    /// Free cells, in index order.
+    pub fn legal_moves(&self) -> impl Iterator<Item = usize> {
+        self.cells.without(&self.taken).iter()
+    }
+
+    pub fn played_cells(&self) -> impl Iterator<Item = usize> {
+        self.taken.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(width: usize, height: usize, win_length: usize, a: &[usize], b: &[usize]) -> GameBoard {
+        let mut board = GameBoard::new(BoardSize::new(width, height, win_length).unwrap());
+        for &index in a {
+            assert!(board.update_cell(index, CellOwner::PlayerA));
+        }
+        for &index in b {
+            assert!(board.update_cell(index, CellOwner::PlayerB));
+        }
+        board
+    }
+
+    #[test]
+    fn row_wins_on_wide_board() {
+        // 5x3, four in a row: the middle row
+        let won = board(5, 3, 4, &[6, 7, 8, 9], &[0, 1, 2]);
+        assert!(won.check_winner() == CellOwner::PlayerA);
+        assert_eq!(won.completed_lines(CellOwner::PlayerA), vec![vec![6, 7, 8, 9]]);
+        // a full column is only three cells long
+        assert!(board(5, 3, 4, &[0, 5, 10], &[]).check_winner() == CellOwner::None);
+    }
+
+    #[test]
+    fn column_wins_on_tall_board() {
+        // 3x5, four in a row: the middle column
+        let won = board(3, 5, 4, &[0, 2], &[1, 4, 7, 10]);
+        assert!(won.check_winner() == CellOwner::PlayerB);
+        assert_eq!(won.completed_lines(CellOwner::PlayerB), vec![vec![1, 4, 7, 10]]);
+    }
+
+    #[test]
+    fn lines_do_not_wrap_around_rows() {
+        // end of the first row and start of the second one
+        assert!(board(5, 3, 4, &[3, 4, 5, 6], &[]).check_winner() == CellOwner::None);
+        assert!(board(5, 3, 4, &[2, 3, 4, 5], &[]).check_winner() == CellOwner::None);
+    }
+
+    #[test]
+    fn both_diagonals_win() {
+        let down_right = board(4, 4, 3, &[5, 10, 15], &[0, 1]);
+        assert!(down_right.check_winner() == CellOwner::PlayerA);
+        assert_eq!(down_right.completed_lines(CellOwner::PlayerA), vec![vec![5, 10, 15]]);
+        let down_left = board(4, 4, 3, &[0, 1], &[3, 6, 9]);
+        assert!(down_left.check_winner() == CellOwner::PlayerB);
+        assert_eq!(down_left.completed_lines(CellOwner::PlayerB), vec![vec![3, 6, 9]]);
+    }
+
+    #[test]
+    fn full_board_without_line_is_a_tie() {
+        // X O X
+        // X O O
+        // O X X
+        let tie = board(3, 3, 3, &[0, 2, 3, 7, 8], &[1, 4, 5, 6]);
+        assert!(tie.check_winner() == CellOwner::Tie);
+        assert!(tie.check_outcome().lines.is_empty());
+        assert_eq!(tie.legal_moves().count(), 0);
+    }
+
+    #[test]
+    fn legal_moves_are_the_free_cells() {
+        let mut played = board(3, 3, 3, &[4], &[0]);
+        assert_eq!(played.legal_moves().collect::<Vec<_>>(), vec![1, 2, 3, 5, 6, 7, 8]);
+        assert!(!played.update_cell(4, CellOwner::PlayerB));
+        assert!(!played.update_cell(9, CellOwner::PlayerB));
+        assert_eq!(played.played_cells().collect::<Vec<_>>(), vec![0, 4]);
+    }
+
+    #[test]
+    fn undo_move_frees_the_cell() {
+        let mut won = board(3, 3, 3, &[0, 1, 2], &[3, 4]);
+        assert!(won.check_winner() == CellOwner::PlayerA);
+        assert!(won.undo_move(2));
+        assert!(won.check_winner() == CellOwner::None);
+        assert!(won.owner(2) == CellOwner::None);
+        assert!(won.legal_moves().any(|index| index == 2));
+        // only taken cells can be freed
+        assert!(!won.undo_move(2));
+        assert!(!won.undo_move(9));
+        assert!(won.update_cell(2, CellOwner::PlayerB));
+        assert!(won.owner(2) == CellOwner::PlayerB);
     }
 }
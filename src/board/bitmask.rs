@@ -0,0 +1,54 @@
+const WORDS: usize = 4;
+
+/// One bit per cell, enough for the largest 15x15 board.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+pub struct Bitmask([u64; WORDS]);
+
+impl Bitmask {
+    pub const CAPACITY: usize = WORDS * 64;
+
+    /// The first count cells.
+    pub fn first(count: usize) -> Bitmask {
+        let mut mask = Bitmask::default();
+        for index in 0..count {
+            mask.insert(index);
+        }
+        mask
+    }
+
+    pub fn insert(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.0[index / 64] &= !(1 << (index % 64));
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn is_subset(&self, other: &Bitmask) -> bool {
+        self.0.iter().zip(&other.0).all(|(a, b)| a & !b == 0)
+    }
+
+    pub fn without(&self, other: &Bitmask) -> Bitmask {
+        Bitmask(std::array::from_fn(|word| self.0[word] & !other.0[word]))
+    }
+
+    /// Indexes of the set cells, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let words = self.0;
+        (0..WORDS).flat_map(move |word| {
+            let mut bits = words[word];
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some(word * 64 + bit)
+            })
+        })
+    }
+}
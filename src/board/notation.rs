@@ -0,0 +1,365 @@
+use crate::board::CellOwner;
+use crate::ultimate::UltimateBoard;
+use crate::variant::{Variant, VariantBoard};
+use std::fmt;
+
+pub const X_WON: &str = "1-0";
+pub const O_WON: &str = "0-1";
+pub const TIE: &str = "1/2-1/2";
+pub const UNFINISHED: &str = "*";
+const RESULTS: [&str; 4] = [X_WON, O_WON, TIE, UNFINISHED];
+const ULTIMATE_SIDE: usize = 9;
+
+/// A game as text, in the spirit of chess PGN:
+///
+/// ```text
+/// [Variant "3,3,3"]
+/// [X "alice"]
+/// [O "bob"]
+/// [Date "2024.05.01"]
+/// [Result "1-0"]
+///
+/// 1. b2 a1 2. c3 a3 3. a2 c1 4. b1 1-0
+/// ```
+///
+/// Squares are a column letter and a row number counted from the top left
+/// corner. Ultimate games name squares on the 9x9 grid of all small board cells.
+pub struct Notation {
+    pub variant: Variant,
+    pub player_x: String,
+    pub player_o: String,
+    /// As "YYYY.MM.DD", "????.??.??" when unknown.
+    pub date: String,
+    /// One of X_WON, O_WON, TIE or UNFINISHED.
+    pub result: String,
+    /// Why the game ended if not on the board, as "time forfeit" or "abandoned".
+    pub termination: Option<String>,
+    /// In the client move encoding, X moving first.
+    pub moves: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum NotationError {
+    MissingVariant,
+    InvalidVariant(String),
+    InvalidTag(String),
+    InvalidSquare(String),
+    InvalidResult(String),
+    /// Move number and square of a move the rules do not allow.
+    IllegalMove(usize, String),
+    MoveAfterEnd(usize),
+    ResultMismatch { declared: String, actual: String },
+}
+
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotationError::MissingVariant => write!(f, "missing Variant tag"),
+            NotationError::InvalidVariant(variant) => write!(f, "unknown variant {:?}", variant),
+            NotationError::InvalidTag(line) => write!(f, "invalid tag {:?}", line),
+            NotationError::InvalidSquare(square) => write!(f, "invalid square {:?}", square),
+            NotationError::InvalidResult(result) => write!(f, "invalid result {:?}", result),
+            NotationError::IllegalMove(number, square) => write!(f, "illegal move {} at ply {}", square, number),
+            NotationError::MoveAfterEnd(number) => write!(f, "move at ply {} after the end of the game", number),
+            NotationError::ResultMismatch { declared, actual } =>
+                write!(f, "result {} does not match the board, which gives {}", declared, actual),
+        }
+    }
+}
+
+impl Notation {
+    /// The result tag for a finished game, winner being "X", "O" or None for a tie.
+    pub fn result_of(winner: Option<&str>) -> &'static str {
+        match winner {
+            Some("X") => X_WON,
+            Some(_) => O_WON,
+            None => TIE,
+        }
+    }
+
+    /// The Termination tag for a stored result name, None when decided on the board.
+    pub fn termination_of(result: &str) -> Option<String> {
+        match result {
+            "TIMEOUT" => Some(String::from("time forfeit")),
+            "WITHDRAWAL" => Some(String::from("abandoned")),
+            "RESIGNATION" => Some(String::from("resignation")),
+            "AGREED_DRAW" => Some(String::from("draw agreed")),
+            _ => None,
+        }
+    }
+
+    pub fn date_of(unix_seconds: u64) -> String {
+        let (year, month, day) = civil_from_days((unix_seconds / 86400) as i64);
+        format!("{:04}.{:02}.{:02}", year, month, day)
+    }
+
+    pub fn parse(text: &str) -> Result<Notation, NotationError> {
+        let mut variant = None;
+        let mut notation = Notation {
+            // replaced by the mandatory Variant tag
+            variant: Variant::Ultimate,
+            player_x: String::from("?"),
+            player_o: String::from("?"),
+            date: String::from("????.??.??"),
+            result: String::from(UNFINISHED),
+            termination: None,
+            moves: Vec::new(),
+        };
+        let mut movetext = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if !line.starts_with('[') {
+                movetext.push(line);
+                continue;
+            }
+            let (name, value) = parse_tag(line).ok_or_else(|| NotationError::InvalidTag(String::from(line)))?;
+            match name {
+                "Variant" => variant = Some(Variant::parse(&value).ok_or(NotationError::InvalidVariant(value))?),
+                "X" => notation.player_x = value,
+                "O" => notation.player_o = value,
+                "Date" => notation.date = value,
+                "Result" if RESULTS.contains(&value.as_str()) => notation.result = value,
+                "Result" => return Err(NotationError::InvalidResult(value)),
+                "Termination" => notation.termination = Some(value),
+                // unknown tags are kept by other tools, not by us
+                _ => (),
+            }
+        }
+        notation.variant = variant.ok_or(NotationError::MissingVariant)?;
+        for token in movetext.iter().flat_map(|line| line.split_whitespace()) {
+            if RESULTS.contains(&token) {
+                if notation.result != UNFINISHED && notation.result != token {
+                    return Err(NotationError::ResultMismatch {
+                        declared: notation.result,
+                        actual: String::from(token),
+                    });
+                }
+                notation.result = String::from(token);
+                break;
+            }
+            // move numbers, either alone as "1." or glued as "1.b2"
+            let token = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+            if token.is_empty() {
+                continue;
+            }
+            let move_text = parse_square(notation.variant, token)
+                .ok_or_else(|| NotationError::InvalidSquare(String::from(token)))?;
+            notation.moves.push(move_text);
+        }
+        Ok(notation)
+    }
+
+    /// Plays the moves on an empty board, checking each of them and the
+    /// declared result against the rules.
+    pub fn replay(&self) -> Result<VariantBoard, NotationError> {
+        let mut board = VariantBoard::new(self.variant);
+        let mut player = CellOwner::PlayerA;
+        for (index, move_text) in self.moves.iter().enumerate() {
+            if board.check_winner() != CellOwner::None {
+                return Err(NotationError::MoveAfterEnd(index + 1));
+            }
+            if board.play(move_text, player).is_none() {
+                let square = square_name(self.variant, move_text).unwrap_or_else(|| move_text.clone());
+                return Err(NotationError::IllegalMove(index + 1, square));
+            }
+            player = player.opponent();
+        }
+        let actual = match board.check_winner() {
+            CellOwner::PlayerA => X_WON,
+            CellOwner::PlayerB => O_WON,
+            CellOwner::Tie => TIE,
+            // a game stopped early may have any result
+            CellOwner::None => return Ok(board),
+        };
+        if self.result != actual {
+            return Err(NotationError::ResultMismatch { declared: self.result.clone(), actual: String::from(actual) });
+        }
+        Ok(board)
+    }
+}
+
+impl fmt::Display for Notation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_tag(f, "Variant", &self.variant.to_string())?;
+        write_tag(f, "X", &self.player_x)?;
+        write_tag(f, "O", &self.player_o)?;
+        write_tag(f, "Date", &self.date)?;
+        write_tag(f, "Result", &self.result)?;
+        if let Some(termination) = &self.termination {
+            write_tag(f, "Termination", termination)?;
+        }
+        writeln!(f)?;
+        for (number, pair) in self.moves.chunks(2).enumerate() {
+            write!(f, "{}.", number + 1)?;
+            for move_text in pair {
+                write!(f, " {}", square_name(self.variant, move_text).unwrap_or_else(|| move_text.clone()))?;
+            }
+            write!(f, " ")?;
+        }
+        writeln!(f, "{}", self.result)
+    }
+}
+
+fn write_tag(f: &mut fmt::Formatter, name: &str, value: &str) -> fmt::Result {
+    writeln!(f, "[{} \"{}\"]", name, value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Name and unescaped value of a [Name "value"] line.
+fn parse_tag(line: &str) -> Option<(&str, String)> {
+    let (name, value) = line.strip_prefix('[')?.strip_suffix(']')?.split_once(' ')?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(if c == '\\' { chars.next()? } else { c });
+    }
+    Some((name, unescaped))
+}
+
+/// Name of a square such as "b2", from a move in the client encoding.
+pub fn square_name(variant: Variant, move_text: &str) -> Option<String> {
+    let (x, y) = match variant {
+        Variant::Standard(size) => {
+            let index: usize = move_text.parse().ok()?;
+            (index % size.width, index / size.width)
+        }
+        Variant::Ultimate => {
+            let (board, cell) = UltimateBoard::parse_move(move_text)?;
+            (board % 3 * 3 + cell % 3, board / 3 * 3 + cell / 3)
+        }
+    };
+    Some(format!("{}{}", (b'a' + x as u8) as char, y + 1))
+}
+
+fn parse_square(variant: Variant, square: &str) -> Option<String> {
+    let column = square.chars().next().filter(char::is_ascii_lowercase)?;
+    let x = (column as u8 - b'a') as usize;
+    let y = square[1..].parse::<usize>().ok()?.checked_sub(1)?;
+    match variant {
+        Variant::Standard(size) => (x < size.width && y < size.height)
+            .then(|| (y * size.width + x).to_string()),
+        Variant::Ultimate => (x < ULTIMATE_SIDE && y < ULTIMATE_SIDE)
+            .then(|| UltimateBoard::format_move((y / 3 * 3 + x / 3, y % 3 * 3 + x % 3))),
+    }
+}
+
+/// Year, month and day of a day count since 1970-01-01 (proleptic Gregorian calendar).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::BoardSize;
+
+    fn notation(variant: Variant, moves: &[&str], result: &str) -> Notation {
+        Notation {
+            variant,
+            player_x: String::from("alice"),
+            player_o: String::from("bob"),
+            date: String::from("2024.05.01"),
+            result: String::from(result),
+            termination: None,
+            moves: moves.iter().map(|&move_text| String::from(move_text)).collect(),
+        }
+    }
+
+    fn round_trip(original: &Notation) -> Notation {
+        let parsed = Notation::parse(&original.to_string()).unwrap();
+        assert!(parsed.variant == original.variant);
+        assert_eq!(parsed.player_x, original.player_x);
+        assert_eq!(parsed.player_o, original.player_o);
+        assert_eq!(parsed.date, original.date);
+        assert_eq!(parsed.result, original.result);
+        assert_eq!(parsed.termination, original.termination);
+        assert_eq!(parsed.moves, original.moves);
+        parsed
+    }
+
+    #[test]
+    fn classic_game_round_trips() {
+        let mut game = notation(Variant::Standard(BoardSize::CLASSIC), &["4", "0", "8", "2", "1", "6", "3"], UNFINISHED);
+        game.termination = Some(String::from("abandoned"));
+        assert!(game.to_string().contains("1. b2 a1 2. c3 c1 3. b1 a3 4. a2 *"));
+        round_trip(&game).replay().unwrap();
+    }
+
+    #[test]
+    fn large_board_game_round_trips() {
+        let size = BoardSize::new(15, 15, 5).unwrap();
+        let game = notation(Variant::Standard(size), &["112", "0", "224", "14"], UNFINISHED);
+        assert!(game.to_string().contains("1. h8 a1 2. o15 o1 *"));
+        round_trip(&game).replay().unwrap();
+    }
+
+    #[test]
+    fn ultimate_game_round_trips() {
+        let game = notation(Variant::Ultimate, &["0-4", "4-0", "0-8", "8-0"], UNFINISHED);
+        assert!(game.to_string().contains("1. b2 d4 2. c3 g7 *"));
+        round_trip(&game).replay().unwrap();
+    }
+
+    #[test]
+    fn finished_game_round_trips() {
+        let game = notation(Variant::Standard(BoardSize::CLASSIC), &["0", "3", "1", "4", "2"], X_WON);
+        let board = round_trip(&game).replay().unwrap();
+        assert!(board.check_winner() == CellOwner::PlayerA);
+    }
+
+    #[test]
+    fn tag_values_are_escaped() {
+        let mut game = notation(Variant::Standard(BoardSize::CLASSIC), &[], UNFINISHED);
+        game.player_x = String::from(r#"the "best" \ player"#);
+        assert!(game.to_string().contains(r#"[X "the \"best\" \\ player"]"#));
+        round_trip(&game);
+    }
+
+    #[test]
+    fn taken_square_is_an_illegal_move() {
+        let game = notation(Variant::Standard(BoardSize::CLASSIC), &["4", "0", "4"], UNFINISHED);
+        assert!(matches!(game.replay(), Err(NotationError::IllegalMove(3, square)) if square == "b2"));
+    }
+
+    #[test]
+    fn ultimate_move_on_the_wrong_board_is_illegal() {
+        // the first move sends to the middle board
+        let game = notation(Variant::Ultimate, &["0-4", "0-0"], UNFINISHED);
+        assert!(matches!(game.replay(), Err(NotationError::IllegalMove(2, _))));
+    }
+
+    #[test]
+    fn move_after_a_win_is_rejected() {
+        let game = notation(Variant::Standard(BoardSize::CLASSIC), &["0", "3", "1", "4", "2", "5"], X_WON);
+        assert!(matches!(game.replay(), Err(NotationError::MoveAfterEnd(6))));
+    }
+
+    #[test]
+    fn result_must_match_the_board() {
+        let game = notation(Variant::Standard(BoardSize::CLASSIC), &["0", "3", "1", "4", "2"], O_WON);
+        assert!(matches!(
+            game.replay(),
+            Err(NotationError::ResultMismatch { declared, actual }) if declared == O_WON && actual == X_WON
+        ));
+    }
+
+    #[test]
+    fn result_tag_must_match_the_movetext() {
+        let text = "[Variant \"3,3,3\"]\n[Result \"1-0\"]\n\n1. a1 a2 0-1\n";
+        assert!(matches!(Notation::parse(text), Err(NotationError::ResultMismatch { .. })));
+    }
+
+    #[test]
+    fn unknown_variant_is_rejected() {
+        assert!(matches!(Notation::parse("[Variant \"2,2,2\"]\n\n*\n"), Err(NotationError::InvalidVariant(_))));
+        assert!(matches!(Notation::parse("1. a1 *\n"), Err(NotationError::MissingVariant)));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::actor::{SessionCommand, SessionHandle};
+use crate::board::{CellOwner, GameBoard};
+use crate::message::{ClientMessage, ServerMessage};
+use crate::session::GameSessionPhase;
+use crate::ultimate::UltimateBoard;
+use crate::variant::VariantBoard;
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::StreamExt;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+const WIN_SCORE: i32 = 1000;
+/// Boards with more cells than this only consider cells next to a played one.
+const FULL_WIDTH_CELLS: usize = 16;
+/// Upper bound on positions visited times cells per position for a single
+/// search, so that larger boards get a shallower search instead of a frozen bot.
+const SEARCH_BUDGET: usize = 4_000_000;
+
+#[derive(Clone, Copy)]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+    Perfect,
+}
+
+impl Difficulty {
+    pub fn from_name(name: &str) -> Option<Difficulty> {
+        match name {
+            "easy" => Some(Difficulty::Easy),
+            "medium" => Some(Difficulty::Medium),
+            "hard" => Some(Difficulty::Hard),
+            "perfect" => Some(Difficulty::Perfect),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+            Difficulty::Perfect => "perfect",
+        }
+    }
+
+    /// Chance of playing a random cell instead of the searched one.
+    fn mistake_probability(&self) -> f64 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Medium => 0.25,
+            Difficulty::Hard => 0.1,
+            Difficulty::Perfect => 0.0,
+        }
+    }
+
+    /// How many plies ahead the bot looks, None meaning as deep as SEARCH_BUDGET allows.
+    fn search_depth(&self) -> Option<i32> {
+        match self {
+            Difficulty::Easy => Some(1),
+            Difficulty::Medium => Some(2),
+            Difficulty::Hard => Some(4),
+            Difficulty::Perfect => None,
+        }
+    }
+}
+
+/// What the search needs to know about a rule set.
+pub trait SearchBoard: Clone {
+    type Move: Copy;
+
+    /// Legal moves worth searching, best guesses first.
+    fn candidate_moves(&self) -> Vec<Self::Move>;
+    fn play(&mut self, mv: Self::Move, owner: CellOwner);
+    fn winner(&self) -> CellOwner;
+    fn cell_count(&self) -> usize;
+}
+
+#[derive(Clone, Copy)]
+pub struct Bot {
+    player: CellOwner,
+    difficulty: Difficulty,
+}
+
+impl Bot {
+    pub fn new(player: CellOwner, difficulty: Difficulty) -> Bot {
+        Bot { player, difficulty }
+    }
+
+    pub fn next_move<B: SearchBoard>(&self, board: &B) -> Option<B::Move> {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.difficulty.mistake_probability()) {
+            board.candidate_moves().choose(&mut rng).copied()
+        } else {
+            self.best_move(board)
+        }
+    }
+
+    pub fn best_move<B: SearchBoard>(&self, board: &B) -> Option<B::Move> {
+        let candidates = board.candidate_moves();
+        let depth_limit = Self::affordable_depth(candidates.len(), board.cell_count());
+        let depth_limit = match self.difficulty.search_depth() {
+            Some(limit) => limit.min(depth_limit),
+            None => depth_limit,
+        };
+        let mut best_move = None;
+        let mut best_score = i32::MIN;
+        for mv in candidates {
+            let mut next = board.clone();
+            next.play(mv, self.player);
+            let score = self.minimax(&next, self.player.opponent(), 1, depth_limit, i32::MIN, i32::MAX);
+            if best_move.is_none() || score > best_score {
+                best_move = Some(mv);
+                best_score = score;
+            }
+        }
+        best_move
+    }
+
+    /// Alpha-beta search scored from the bot point of view: quicker wins and
+    /// slower losses are preferred.
+    fn minimax<B: SearchBoard>(&self, board: &B, to_move: CellOwner, depth: i32, depth_limit: i32, mut alpha: i32, mut beta: i32) -> i32 {
+        let winner = board.winner();
+        if winner == self.player {
+            return WIN_SCORE - depth;
+        } else if winner == self.player.opponent() {
+            return depth - WIN_SCORE;
+        } else if winner == CellOwner::Tie {
+            return 0;
+        }
+        if depth >= depth_limit {
+            return 0;
+        }
+
+        let maximizing = to_move == self.player;
+        let mut best = if maximizing { i32::MIN } else { i32::MAX };
+        for mv in board.candidate_moves() {
+            let mut next = board.clone();
+            next.play(mv, to_move);
+            let score = self.minimax(&next, to_move.opponent(), depth + 1, depth_limit, alpha, beta);
+            if maximizing {
+                best = best.max(score);
+                alpha = alpha.max(best);
+            } else {
+                best = best.min(score);
+                beta = beta.min(best);
+            }
+            if beta <= alpha {
+                break;
+            }
+        }
+        best
+    }
+
+    /// Deepest search over the given moves that stays within SEARCH_BUDGET.
+    fn affordable_depth(moves: usize, cell_count: usize) -> i32 {
+        let node_budget = SEARCH_BUDGET / cell_count;
+        let mut depth = 0;
+        let mut nodes: usize = 1;
+        while depth < moves {
+            nodes = nodes.saturating_mul(moves - depth);
+            if nodes > node_budget {
+                break;
+            }
+            depth += 1;
+        }
+        depth.max(1) as i32
+    }
+}
+
+impl SearchBoard for GameBoard {
+    type Move = usize;
+
+    /// Free cells, most central first so that equally scored moves resolve
+    /// towards the middle of the board.
+    fn candidate_moves(&self) -> Vec<usize> {
+        let size = self.size();
+        let coordinates = |index: usize| ((index % size.width) as isize, (index / size.width) as isize);
+        let near_played = |index: usize| {
+            let (x, y) = coordinates(index);
+            self.played_cells().any(|played| {
+                let (px, py) = coordinates(played);
+                (px - x).abs() <= 1 && (py - y).abs() <= 1
+            })
+        };
+        let mut candidates: Vec<usize> = if size.cell_count() <= FULL_WIDTH_CELLS || self.played_cells().next().is_none() {
+            self.legal_moves().collect()
+        } else {
+            self.legal_moves().filter(|&index| near_played(index)).collect()
+        };
+        let (cx, cy) = ((size.width as isize - 1) / 2, (size.height as isize - 1) / 2);
+        candidates.sort_by_key(|&index| {
+            let (x, y) = coordinates(index);
+            (x - cx).abs().max((y - cy).abs())
+        });
+        candidates
+    }
+
+    fn play(&mut self, mv: usize, owner: CellOwner) {
+        self.update_cell(mv, owner);
+    }
+
+    fn winner(&self) -> CellOwner {
+        self.check_winner()
+    }
+
+    fn cell_count(&self) -> usize {
+        self.size().cell_count()
+    }
+}
+
+impl SearchBoard for UltimateBoard {
+    type Move = (usize, usize);
+
+    fn candidate_moves(&self) -> Vec<(usize, usize)> {
+        self.legal_moves()
+    }
+
+    fn play(&mut self, mv: (usize, usize), owner: CellOwner) {
+        self.update_cell(mv, owner);
+    }
+
+    fn winner(&self) -> CellOwner {
+        self.check_winner()
+    }
+
+    fn cell_count(&self) -> usize {
+        self.cell_count()
+    }
+}
+
+/// Picks a move in the text encoding used by the client.
+fn pick_move(bot: Bot, board: &VariantBoard) -> Option<String> {
+    match board {
+        VariantBoard::Standard(board) => bot.next_move(board).map(|index| index.to_string()),
+        VariantBoard::Ultimate(board) => bot.next_move(board).map(UltimateBoard::format_move),
+    }
+}
+
+/// Plays the PlayerB seat of a session: the bot listens on its own channel
+/// like a regular client would and answers whenever the turn is its own.
+pub fn spawn_bot(
+    session: SessionHandle,
+    mut rx: UnboundedReceiver<ServerMessage>,
+    difficulty: Difficulty,
+) {
+    tokio::spawn(async move {
+        let bot = Bot::new(CellOwner::PlayerB, difficulty);
+        while let Some(msg) = rx.next().await {
+            match msg {
+                ServerMessage::End(_) => break,
+                ServerMessage::Rematch(_) => {
+                    // the computer never turns a rematch down
+                    session.send(SessionCommand::Input { player: CellOwner::PlayerB, message: ClientMessage::Rematch });
+                    continue;
+                }
+                ServerMessage::Takeback(_) => {
+                    // nor a takeback
+                    session.send(SessionCommand::Input { player: CellOwner::PlayerB, message: ClientMessage::AcceptTakeback });
+                    continue;
+                }
+                ServerMessage::DrawOffer(_) => {
+                    // but it plays every game to the end
+                    session.send(SessionCommand::Input { player: CellOwner::PlayerB, message: ClientMessage::DeclineDraw });
+                    continue;
+                }
+                _ => (),
+            }
+            let Some((phase, turn, board)) = session.request(|reply| SessionCommand::Snapshot { reply }).await else {
+                break;
+            };
+            if phase != GameSessionPhase::PLAYING || turn != CellOwner::PlayerB {
+                continue;
+            }
+            // the search can be long on big boards: keep it off the session task and the async workers
+            let next_move = tokio::task::spawn_blocking(move || pick_move(bot, &board)).await
+                .unwrap_or(None);
+            match next_move {
+                Some(move_text) => {
+                    session.send(SessionCommand::Input {
+                        player: CellOwner::PlayerB,
+                        message: ClientMessage::ClientClick { text: move_text },
+                    });
+                }
+                None => println!("Bot found no move to play"),
+            }
+        }
+        println!("Bot left the game");
+    });
+}
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const MAX_CHAT_LENGTH: usize = 200;
+/// At most CHAT_BURST messages per player within CHAT_WINDOW.
+const CHAT_BURST: usize = 5;
+const CHAT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Why a chat message was not relayed.
+pub enum ChatError {
+    Empty,
+    TooLong,
+    TooFast,
+    NoOpponent,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatError::Empty => write!(f, "empty chat message"),
+            ChatError::TooLong => write!(f, "chat messages have at most {} characters", MAX_CHAT_LENGTH),
+            ChatError::TooFast => write!(f, "at most {} chat messages every {} seconds", CHAT_BURST, CHAT_WINDOW.as_secs()),
+            ChatError::NoOpponent => write!(f, "there is no one to chat with"),
+        }
+    }
+}
+
+/// Recent messages of one player, for the rate limit.
+#[derive(Default)]
+pub struct ChatLimiter {
+    sent: VecDeque<Instant>,
+}
+
+impl ChatLimiter {
+    /// Counts the message if the player is still within the limit.
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while self.sent.front().is_some_and(|&sent| now.duration_since(sent) >= CHAT_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= CHAT_BURST {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+/// The message ready to be shown by the client, which injects it as html.
+pub fn clean_message(text: &str) -> Result<String, ChatError> {
+    let text: String = text.trim().chars().filter(|c| !c.is_control()).collect();
+    if text.is_empty() {
+        Err(ChatError::Empty)
+    } else if text.chars().count() > MAX_CHAT_LENGTH {
+        Err(ChatError::TooLong)
+    } else {
+        Ok(escape_html(&text))
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(escape_html(r#"<b onclick="x('y')">&</b>"#), "&lt;b onclick=&quot;x(&#39;y&#39;)&quot;&gt;&amp;&lt;/b&gt;");
+        assert_eq!(escape_html("gg, well played"), "gg, well played");
+    }
+
+    #[test]
+    fn message_is_trimmed_and_escaped() {
+        assert!(matches!(clean_message("  hi\u{7} <3 \n").as_deref(), Ok("hi &lt;3")));
+        assert!(matches!(clean_message(" \t\n"), Err(ChatError::Empty)));
+    }
+
+    #[test]
+    fn length_is_counted_in_characters() {
+        let longest = "é".repeat(MAX_CHAT_LENGTH);
+        assert!(clean_message(&longest).is_ok());
+        assert!(matches!(clean_message(&format!("{}e", longest)), Err(ChatError::TooLong)));
+        // the limit applies before escaping
+        assert!(clean_message(&"<".repeat(MAX_CHAT_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn limiter_allows_a_burst_per_window() {
+        let mut limiter = ChatLimiter::default();
+        for _ in 0..CHAT_BURST {
+            assert!(limiter.allow());
+        }
+        assert!(!limiter.allow());
+        // refused messages do not count
+        assert_eq!(limiter.sent.len(), CHAT_BURST);
+    }
+
+    #[test]
+    fn limiter_forgets_messages_out_of_the_window() {
+        let mut limiter = ChatLimiter::default();
+        let expired = Instant::now().checked_sub(CHAT_WINDOW).unwrap();
+        limiter.sent.extend([expired; CHAT_BURST]);
+        assert!(limiter.allow());
+        assert_eq!(limiter.sent.len(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::board::CellOwner;
+use crate::session::seat;
+use std::time::{Duration, Instant};
+
+const MIN_SECONDS: u64 = 5;
+const MAX_SECONDS: u64 = 3600;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub enum TimeControl {
+    /// Every move has to be played within the limit.
+    PerMove(Duration),
+    /// Chess style: each player has a budget for the whole game.
+    Total(Duration),
+}
+
+impl TimeControl {
+    /// Default limits are 30 seconds per move or 5 minutes per player.
+    pub fn from_name(name: &str, seconds: Option<u64>) -> Option<TimeControl> {
+        let limit = |default| {
+            let seconds = seconds.unwrap_or(default);
+            (MIN_SECONDS..=MAX_SECONDS).contains(&seconds).then(|| Duration::from_secs(seconds))
+        };
+        match name {
+            "move" => limit(30).map(TimeControl::PerMove),
+            "total" => limit(300).map(TimeControl::Total),
+            _ => None,
+        }
+    }
+
+    fn limit(&self) -> Duration {
+        match *self {
+            TimeControl::PerMove(limit) | TimeControl::Total(limit) => limit,
+        }
+    }
+}
+
+/// Time left to each player, only the player to move is running.
+pub struct Clock {
+    time_control: TimeControl,
+    remaining: [Duration; 2],
+    running: CellOwner,
+    turn_started: Instant,
+}
+
+impl Clock {
+    pub fn new(time_control: TimeControl) -> Clock {
+        Clock {
+            time_control,
+            remaining: [time_control.limit(); 2],
+            running: CellOwner::PlayerA,
+            turn_started: Instant::now(),
+        }
+    }
+
+    pub fn time_control(&self) -> TimeControl {
+        self.time_control
+    }
+
+    /// Resets both players to the full limit, for a new game.
+    pub fn start(&mut self, first_player: CellOwner) {
+        self.remaining = [self.time_control.limit(); 2];
+        self.running = first_player;
+        self.turn_started = Instant::now();
+    }
+
+    /// Stops the clock of the player who just moved and starts the opponent's.
+    pub fn switch(&mut self) {
+        self.switch_at(Instant::now());
+    }
+
+    pub fn left(&self, player: CellOwner) -> Duration {
+        self.left_at(player, Instant::now())
+    }
+
+    /// The player to move ran out of time.
+    pub fn is_flagged(&self) -> bool {
+        self.is_flagged_at(Instant::now())
+    }
+
+    fn switch_at(&mut self, now: Instant) {
+        let next = self.running.opponent();
+        match self.time_control {
+            TimeControl::Total(_) => self.remaining[seat(self.running)] = self.left_at(self.running, now),
+            TimeControl::PerMove(limit) => self.remaining[seat(next)] = limit,
+        }
+        self.running = next;
+        self.turn_started = now;
+    }
+
+    fn left_at(&self, player: CellOwner, now: Instant) -> Duration {
+        if player == self.running {
+            self.remaining[seat(player)].saturating_sub(now.saturating_duration_since(self.turn_started))
+        } else {
+            self.remaining[seat(player)]
+        }
+    }
+
+    fn is_flagged_at(&self, now: Instant) -> bool {
+        self.left_at(self.running, now).is_zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// A clock started with X to move, and the instant it started.
+    fn clock(time_control: TimeControl) -> (Clock, Instant) {
+        let mut clock = Clock::new(time_control);
+        clock.start(CellOwner::PlayerA);
+        let started = clock.turn_started;
+        (clock, started)
+    }
+
+    #[test]
+    fn total_time_is_used_up_across_moves() {
+        let (mut clock, t0) = clock(TimeControl::Total(10 * SECOND));
+        assert_eq!(clock.left_at(CellOwner::PlayerA, t0 + 3 * SECOND), 7 * SECOND);
+        assert_eq!(clock.left_at(CellOwner::PlayerB, t0 + 3 * SECOND), 10 * SECOND);
+        clock.switch_at(t0 + 3 * SECOND);
+        // X is stopped, O is running
+        assert_eq!(clock.left_at(CellOwner::PlayerA, t0 + 9 * SECOND), 7 * SECOND);
+        assert_eq!(clock.left_at(CellOwner::PlayerB, t0 + 9 * SECOND), 4 * SECOND);
+        clock.switch_at(t0 + 9 * SECOND);
+        assert_eq!(clock.left_at(CellOwner::PlayerB, t0 + 20 * SECOND), 4 * SECOND);
+        assert!(!clock.is_flagged_at(t0 + 15 * SECOND));
+        assert!(clock.is_flagged_at(t0 + 16 * SECOND));
+        assert_eq!(clock.left_at(CellOwner::PlayerA, t0 + 60 * SECOND), Duration::ZERO);
+    }
+
+    #[test]
+    fn per_move_time_resets_on_every_switch() {
+        let (mut clock, t0) = clock(TimeControl::PerMove(5 * SECOND));
+        clock.switch_at(t0 + 4 * SECOND);
+        assert_eq!(clock.left_at(CellOwner::PlayerB, t0 + 4 * SECOND), 5 * SECOND);
+        assert_eq!(clock.left_at(CellOwner::PlayerB, t0 + 8 * SECOND), SECOND);
+        clock.switch_at(t0 + 8 * SECOND);
+        // time saved on a move is not carried over
+        assert_eq!(clock.left_at(CellOwner::PlayerA, t0 + 8 * SECOND), 5 * SECOND);
+        assert!(!clock.is_flagged_at(t0 + 12 * SECOND));
+        assert!(clock.is_flagged_at(t0 + 13 * SECOND));
+    }
+
+    #[test]
+    fn start_gives_the_full_limit_back() {
+        let (mut clock, t0) = clock(TimeControl::Total(10 * SECOND));
+        clock.switch_at(t0 + 8 * SECOND);
+        clock.start(CellOwner::PlayerB);
+        assert!(clock.running == CellOwner::PlayerB);
+        assert_eq!(clock.remaining, [10 * SECOND; 2]);
+    }
+
+    #[test]
+    fn limits_are_bounded() {
+        assert!(TimeControl::from_name("move", None) == Some(TimeControl::PerMove(30 * SECOND)));
+        assert!(TimeControl::from_name("total", Some(60)) == Some(TimeControl::Total(60 * SECOND)));
+        assert!(TimeControl::from_name("total", Some(4)).is_none());
+        assert!(TimeControl::from_name("total", Some(3601)).is_none());
+        assert!(TimeControl::from_name("blitz", None).is_none());
+    }
+}
@@ -0,0 +1,390 @@
+use crate::board::CellOwner;
+use crate::rating::{updated_rating, DEFAULT_RATING};
+use crate::stats::{PlayedGame, PlayerStats, RankedPlayer};
+use crate::variant::{Variant, VariantBoard};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Schema changes, applied in order: the number of applied ones is kept in
+/// the user_version pragma. Never edit an entry, append a new one.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE games (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        session_id INTEGER NOT NULL,
+        variant TEXT NOT NULL,
+        player_x TEXT NOT NULL,
+        player_o TEXT NOT NULL,
+        winner TEXT,
+        result TEXT NOT NULL,
+        moves TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL
+    );
+    CREATE INDEX games_ended_at ON games (ended_at);",
+    "CREATE TABLE moves (
+        game_id INTEGER NOT NULL REFERENCES games (id),
+        ply INTEGER NOT NULL,
+        player TEXT NOT NULL,
+        cell TEXT NOT NULL,
+        played_at_ms INTEGER NOT NULL,
+        PRIMARY KEY (game_id, ply)
+    );",
+    "CREATE TABLE accounts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        password_hash TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE logins (
+        token TEXT PRIMARY KEY,
+        account_id INTEGER NOT NULL REFERENCES accounts (id),
+        expires_at INTEGER NOT NULL
+    );
+    ALTER TABLE games ADD COLUMN account_x INTEGER REFERENCES accounts (id);
+    ALTER TABLE games ADD COLUMN account_o INTEGER REFERENCES accounts (id);",
+    "ALTER TABLE accounts ADD COLUMN rating REAL NOT NULL DEFAULT 1500;
+    ALTER TABLE accounts ADD COLUMN rated_games INTEGER NOT NULL DEFAULT 0;",
+];
+
+/// How a game ended, stored with it and sent to the clients as the RESULT reason.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GameResult {
+    /// Decided by a completed line, for the winner and the loser alike.
+    Line,
+    Tie,
+    /// The loser ran out of time.
+    Timeout,
+    /// The loser left the game before the end.
+    Withdrawal,
+    /// The loser gave up.
+    Resignation,
+    /// Both players agreed on a tie before the board was decided.
+    AgreedDraw,
+}
+
+impl GameResult {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameResult::Line => "LINE",
+            GameResult::Tie => "TIE",
+            GameResult::Timeout => "TIMEOUT",
+            GameResult::Withdrawal => "WITHDRAWAL",
+            GameResult::Resignation => "RESIGNATION",
+            GameResult::AgreedDraw => "AGREED_DRAW",
+        }
+    }
+}
+
+/// A game played to the end, as stored in the database.
+#[derive(Clone)]
+pub struct GameRecord {
+    pub session_id: u64,
+    pub variant: String,
+    pub player_x: String,
+    pub player_o: String,
+    /// Registered players, None for guests and the computer.
+    pub account_x: Option<i64>,
+    pub account_o: Option<i64>,
+    /// "X" or "O", None for a tie.
+    pub winner: Option<&'static str>,
+    pub result: GameResult,
+    pub moves: Vec<MoveRecord>,
+    pub started_at: u64,
+    pub ended_at: u64,
+}
+
+/// An accepted move, as kept for replays.
+#[derive(Clone)]
+pub struct MoveRecord {
+    /// "X" or "O".
+    pub player: &'static str,
+    /// In the client move encoding.
+    pub cell: String,
+    /// Server time, in milliseconds since the epoch.
+    pub played_at_ms: u64,
+}
+
+/// Stored game without its moves, as listed to pick a replay from.
+#[derive(Serialize)]
+pub struct GameSummary {
+    pub id: i64,
+    pub variant: String,
+    pub player_x: String,
+    pub player_o: String,
+    pub winner: Option<String>,
+    pub result: String,
+    pub started_at: u64,
+    pub ended_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct Replay {
+    #[serde(flatten)]
+    pub game: GameSummary,
+    pub moves: Vec<ReplayMove>,
+    /// Lines completed by the winner, so that the replay can show how the game was won.
+    pub winning_lines: Vec<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct ReplayMove {
+    pub player: String,
+    pub cell: String,
+    pub played_at_ms: u64,
+}
+
+/// A registered player.
+#[derive(Clone, Serialize)]
+pub struct Account {
+    pub id: i64,
+    pub name: String,
+    pub rating: f64,
+}
+
+const SUMMARY_COLUMNS: &str = "id, variant, player_x, player_o, winner, result, started_at, ended_at";
+
+pub struct GameDatabase {
+    connection: Mutex<Connection>,
+}
+
+impl GameDatabase {
+    /// Opens or creates the database file, bringing its schema up to date.
+    pub fn open(path: &str) -> rusqlite::Result<GameDatabase> {
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection)?;
+        Ok(GameDatabase { connection: Mutex::new(connection) })
+    }
+
+    pub fn save_game(&self, record: &GameRecord) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let cells: Vec<&str> = record.moves.iter().map(|played| played.cell.as_str()).collect();
+        transaction.execute(
+            "INSERT INTO games (session_id, variant, player_x, player_o, account_x, account_o, winner, result, moves, started_at, ended_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                record.session_id,
+                record.variant,
+                record.player_x,
+                record.player_o,
+                record.account_x,
+                record.account_o,
+                record.winner,
+                record.result.name(),
+                cells.join(" "),
+                record.started_at,
+                record.ended_at,
+            ],
+        )?;
+        let game_id = transaction.last_insert_rowid();
+        for (ply, played) in record.moves.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO moves (game_id, ply, player, cell, played_at_ms) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![game_id, ply, played.player, played.cell, played.played_at_ms],
+            )?;
+        }
+        // only games between two different accounts are rated
+        if let (Some(account_x), Some(account_o)) = (record.account_x, record.account_o) {
+            if account_x != account_o {
+                let score_x = match record.winner {
+                    Some("X") => 1.0,
+                    Some(_) => 0.0,
+                    None => 0.5,
+                };
+                update_ratings(&transaction, account_x, account_o, score_x)?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Latest stored games, most recent first.
+    pub fn recent_games(&self, limit: usize) -> rusqlite::Result<Vec<GameSummary>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {} FROM games ORDER BY id DESC LIMIT ?1", SUMMARY_COLUMNS
+        ))?;
+        let games = statement.query_map([limit], summary_of_row)?.collect();
+        games
+    }
+
+    /// A stored game with its moves in the order they were played, None if unknown.
+    pub fn replay(&self, game_id: i64) -> rusqlite::Result<Option<Replay>> {
+        let connection = self.connection.lock().unwrap();
+        let game = connection.query_row(
+            &format!("SELECT {} FROM games WHERE id = ?1", SUMMARY_COLUMNS),
+            [game_id],
+            summary_of_row,
+        ).optional()?;
+        let Some(game) = game else { return Ok(None) };
+        let mut statement = connection.prepare(
+            "SELECT player, cell, played_at_ms FROM moves WHERE game_id = ?1 ORDER BY ply"
+        )?;
+        let moves = statement.query_map([game_id], |row| Ok(ReplayMove {
+            player: row.get(0)?,
+            cell: row.get(1)?,
+            played_at_ms: row.get(2)?,
+        }))?.collect::<rusqlite::Result<Vec<ReplayMove>>>()?;
+        let winning_lines = Variant::parse(&game.variant).map_or_else(Vec::new, |variant| {
+            let mut board = VariantBoard::new(variant);
+            for played in &moves {
+                let owner = if played.player == "X" { CellOwner::PlayerA } else { CellOwner::PlayerB };
+                board.play(&played.cell, owner);
+            }
+            board.check_outcome().lines
+        });
+        Ok(Some(Replay { game, moves, winning_lines }))
+    }
+
+    /// None if the name is already taken, whatever its case.
+    pub fn create_account(&self, name: &str, password_hash: &str) -> rusqlite::Result<Option<Account>> {
+        let connection = self.connection.lock().unwrap();
+        let inserted = connection.execute(
+            "INSERT INTO accounts (name, password_hash, created_at) VALUES (?1, ?2, ?3) ON CONFLICT (name) DO NOTHING",
+            params![name, password_hash, unix_time()],
+        )?;
+        Ok((inserted > 0).then(|| Account {
+            id: connection.last_insert_rowid(),
+            name: String::from(name),
+            rating: DEFAULT_RATING,
+        }))
+    }
+
+    /// The account and its password hash, looked up by name whatever its case.
+    pub fn account_by_name(&self, name: &str) -> rusqlite::Result<Option<(Account, String)>> {
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            "SELECT id, name, rating, password_hash FROM accounts WHERE name = ?1",
+            [name],
+            |row| Ok((Account { id: row.get(0)?, name: row.get(1)?, rating: row.get(2)? }, row.get(3)?)),
+        ).optional()
+    }
+
+    pub fn create_login(&self, token: &str, account_id: i64, expires_at: u64) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM logins WHERE expires_at <= ?1", [unix_time()])?;
+        connection.execute(
+            "INSERT INTO logins (token, account_id, expires_at) VALUES (?1, ?2, ?3)",
+            params![token, account_id, expires_at],
+        )?;
+        Ok(())
+    }
+
+    /// The account logged in with this token, None if unknown or expired.
+    pub fn account_of_login(&self, token: &str) -> rusqlite::Result<Option<Account>> {
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            "SELECT accounts.id, accounts.name, accounts.rating FROM logins JOIN accounts ON accounts.id = logins.account_id
+             WHERE logins.token = ?1 AND logins.expires_at > ?2",
+            params![token, unix_time()],
+            |row| Ok(Account { id: row.get(0)?, name: row.get(1)?, rating: row.get(2)? }),
+        ).optional()
+    }
+
+    /// Best rated accounts with at least one rated game, best first.
+    pub fn top_players(&self, limit: usize) -> rusqlite::Result<Vec<RankedPlayer>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT name, rating, rated_games FROM accounts WHERE rated_games > 0
+             ORDER BY rating DESC, name LIMIT ?1"
+        )?;
+        let players = statement.query_map([limit], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .enumerate()
+            .map(|(index, player)| player.map(|(name, rating, rated_games)| RankedPlayer {
+                rank: index + 1,
+                name,
+                rating,
+                rated_games,
+            }))
+            .collect();
+        players
+    }
+
+    /// Statistics over all stored games of an account, None if unknown.
+    pub fn player_stats(&self, name: &str) -> rusqlite::Result<Option<PlayerStats>> {
+        let connection = self.connection.lock().unwrap();
+        let account = connection.query_row(
+            "SELECT id, name, rating, rated_games FROM accounts WHERE name = ?1",
+            [name],
+            |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ).optional()?;
+        let Some((id, name, rating, rated_games)) = account else { return Ok(None) };
+        let mut statement = connection.prepare(
+            "SELECT variant, CASE WHEN account_x = ?1 THEN 'X' ELSE 'O' END, winner, moves, started_at, ended_at
+             FROM games WHERE account_x = ?1 OR account_o = ?1 ORDER BY id"
+        )?;
+        let games = statement.query_map([id], |row| Ok(PlayedGame {
+            variant: row.get(0)?,
+            figure: row.get(1)?,
+            winner: row.get(2)?,
+            moves: row.get(3)?,
+            started_at: row.get(4)?,
+            ended_at: row.get(5)?,
+        }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Some(PlayerStats::new(name, rating, rated_games, &games)))
+    }
+
+    pub fn delete_login(&self, token: &str) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM logins WHERE token = ?1", [token])?;
+        Ok(())
+    }
+}
+
+/// Elo update of both players, score_x is 1 when X won, 0.5 for a tie.
+fn update_ratings(transaction: &Transaction, account_x: i64, account_o: i64, score_x: f64) -> rusqlite::Result<()> {
+    let rating_of = |account: i64| transaction.query_row(
+        "SELECT rating, rated_games FROM accounts WHERE id = ?1",
+        [account],
+        |row| Ok((row.get::<_, f64>(0)?, row.get::<_, u32>(1)?)),
+    );
+    let (rating_x, games_x) = rating_of(account_x)?;
+    let (rating_o, games_o) = rating_of(account_o)?;
+    let updates = [
+        (account_x, updated_rating(rating_x, rating_o, score_x, games_x)),
+        (account_o, updated_rating(rating_o, rating_x, 1.0 - score_x, games_o)),
+    ];
+    for (account, rating) in updates {
+        transaction.execute(
+            "UPDATE accounts SET rating = ?1, rated_games = rated_games + 1 WHERE id = ?2",
+            params![rating, account],
+        )?;
+    }
+    Ok(())
+}
+
+fn summary_of_row(row: &rusqlite::Row) -> rusqlite::Result<GameSummary> {
+    Ok(GameSummary {
+        id: row.get(0)?,
+        variant: row.get(1)?,
+        player_x: row.get(2)?,
+        player_o: row.get(3)?,
+        winner: row.get(4)?,
+        result: row.get(5)?,
+        started_at: row.get(6)?,
+        ended_at: row.get(7)?,
+    })
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        println!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+/// Seconds since the epoch, as stored in the database.
+pub fn unix_time() -> u64 {
+    unix_time_ms() / 1000
+}
+
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -1,27 +1,44 @@
+mod account;
+mod actor;
 mod board;
+mod bot;
+mod chat;
+mod clock;
+mod database;
+mod matchmaking;
 mod message;
+mod rating;
+mod registry;
 mod session;
+mod stats;
+mod ultimate;
+mod variant;
 mod resources;
 
 use hyper_util::rt::TokioIo;
 
-use board::CellOwner;
-use message::{GameMessageFactory, MessageType};
-use session::{GameSession, GameSessionPhase};
+use account::AccountError;
+use board::notation::Notation;
+use board::{BoardSize, CellOwner};
+use clock::TimeControl;
+use message::{ClientMessage, GameMessageFactory, ServerMessage};
+use session::{GameSession, GameSessionPhase, Player};
+use variant::Variant;
 
-use futures_channel::mpsc::unbounded;
+use futures_channel::mpsc::{unbounded, UnboundedSender};
 use futures_util::{future, stream::TryStreamExt, StreamExt};
+use http_body_util::{BodyExt, Limited};
 use std::convert::Infallible;
+use std::str::FromStr;
 use std::task::Poll;
 use std::{
     env,
     io::Error as IoError,
     net::SocketAddr,
     sync::{Arc, Mutex},
-    time::Duration,
 };
 use std::sync::atomic::{AtomicBool, Ordering};
-use hyper::header::CONTENT_TYPE;
+use hyper::header::{CONTENT_TYPE, SET_COOKIE};
 use hyper::{body::Incoming, header::{
     HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
     UPGRADE,
@@ -34,11 +51,46 @@ use tokio_tungstenite::{
     WebSocketStream,
 };
 
+use crate::actor::{SessionCommand, SessionHandle};
+use crate::bot::{spawn_bot, Difficulty};
+use crate::database::{Account, GameDatabase};
+use crate::matchmaking::{find_lobby, spawn_matchmaking};
+use crate::registry::{spawn_reaper, SessionRegistry};
+use rand::seq::SliceRandom;
 use crate::message::message_send;
 use crate::resources::StaticResource;
+use serde::Deserialize;
 use tokio::net::TcpListener;
 
-type PeerList = Arc<Mutex<Vec<Arc<Mutex<GameSession>>>>>;
+type PeerList = Arc<Mutex<SessionRegistry>>;
+
+/// How the connecting player asked to be seated.
+enum JoinMode {
+    Random,
+    Computer(Difficulty),
+    NewRoom,
+    Room(String),
+    Resume(String),
+    /// Read-only seat on a game, by session id or room code.
+    Spectate(String),
+}
+
+/// What the client asked for in the socket URL.
+struct GameRequest {
+    join_mode: JoinMode,
+    variant: Variant,
+    time_control: Option<TimeControl>,
+}
+
+const DEFAULT_PROTOCOL_ERROR_LIMIT: usize = 20;
+const RECENT_GAMES_LIMIT: usize = 20;
+const LEADERBOARD_LIMIT: usize = 20;
+const MAX_LEADERBOARD_LIMIT: usize = 100;
+const MAX_NOTATION_BYTES: usize = 64 * 1024;
+const MAX_CREDENTIALS_BYTES: usize = 1024;
+const ROOM_CODE_LENGTH: usize = 4;
+// no look-alike letters, codes are meant to be read out loud
+const ROOM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ";
 type Body = http_body_util::Full<hyper::body::Bytes>;
 
 #[tokio::main]
@@ -48,29 +100,37 @@ async fn main() -> Result<(), IoError> {
     // Loads static resources only once
     let resources = StaticResource::new(&js_socket_endpoint).await;
 
-    let game_sessions = PeerList::new(Mutex::new(Vec::with_capacity(6)));
+    let (registry, closed_sessions) = SessionRegistry::new();
+    let game_sessions = PeerList::new(Mutex::new(registry));
+
+    let database_path = env::var("DATABASE_PATH")
+        .unwrap_or_else(|_| String::from("games.db"));
+    let database = Arc::new(GameDatabase::open(&database_path).expect("Failed to open the game database"));
+    println!("Storing finished games in: {}", database_path);
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&listening_addr).await;
     let listener = try_socket.expect("Failed to bind");
     println!("Listening on: {}", listening_addr);
 
-    // Clean closed games job
-    clean_closed_sessions(Arc::clone(&game_sessions));
+    // Closed games are dropped and stored as soon as they end
+    spawn_reaper(Arc::clone(&game_sessions), closed_sessions, Arc::clone(&database));
 
     let game_message_factory = Arc::new(GameMessageFactory::new());
+    spawn_matchmaking(Arc::clone(&game_sessions));
 
     // Handling each connection in a separate task.
     while let Ok((stream, addr)) = listener.accept().await {
         let game_sessions = game_sessions.clone();
         let game_message_factory = Arc::clone(&game_message_factory); // other way of cloning
+        let database = Arc::clone(&database);
 
         tokio::spawn(async move {
             let io = TokioIo::new(stream);
             let conn = http1::Builder::new()
                 .serve_connection(io, service_fn(
                     move |req|
-                        handle_request(req, addr, game_sessions.clone(), game_message_factory.clone(), resources)
+                        handle_request(req, addr, game_sessions.clone(), game_message_factory.clone(), database.clone(), resources)
                 ))
                 .with_upgrades();
             if let Err(err) = conn.await {
@@ -97,48 +157,63 @@ fn addresses() -> (String, String) {
     (js_socket_endpoint, listening_addr)
 }
 
-fn clean_closed_sessions(rc_game_sessions: Arc<Mutex<Vec<Arc<Mutex<GameSession>>>>>) {
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_millis(5000));
-        loop {
-            interval.tick().await;
-            println!("tick");
-            let mut indexes = Vec::new();
-            let mut sessions = rc_game_sessions.lock().unwrap();
-            println!("Before closed cleanup: {}", sessions.len());
-            let mut counter = 0;
-            for (index, el) in sessions.iter().enumerate() {
-                if (*el).lock().unwrap().phase == GameSessionPhase::CLOSED {
-                    indexes.push(index - counter);
-                    counter += 1;
-                }
-            }
-            for i in indexes {
-                sessions.remove(i);
-            }
-            println!("After closed cleanup: {}", sessions.len());
-        }
-    });
-}
-
 async fn handle_request(
     mut req: Request<Incoming>,
     addr: SocketAddr,
     peer_list: PeerList,
     game_message_factory: Arc<GameMessageFactory>,
+    database: Arc<GameDatabase>,
     resources: &'static StaticResource,
 ) -> Result<Response<Body>, Infallible> {
+    if req.method() == Method::POST && req.uri().path() == "/notation" {
+        return import_notation(req).await;
+    }
+    if req.method() == Method::POST && matches!(req.uri().path(), "/account/register" | "/account/login") {
+        return handle_credentials(req, database).await;
+    }
+    if let Some(id) = id_between::<u64>(req.uri().path(), "/live/", "/notation") {
+        return live_notation(id, &peer_list).await;
+    }
     let upgrade = HeaderValue::from_static("Upgrade");
     let websocket = HeaderValue::from_static("websocket");
     let headers = req.headers();
     let key = headers.get(SEC_WEBSOCKET_KEY);
     let derived = key.map(|k| derive_accept_key(k.as_bytes()));
+    let query = req.uri().query();
+    let join_mode = if let Some(token) = query_param(query, "resume") {
+        JoinMode::Resume(String::from(token))
+    } else if let Some(target) = query_param(query, "spectate") {
+        JoinMode::Spectate(target.to_ascii_uppercase())
+    } else if query_param(query, "opponent") == Some("computer") {
+        JoinMode::Computer(query_param(query, "level")
+            .and_then(Difficulty::from_name)
+            .unwrap_or(Difficulty::Perfect))
+    } else {
+        match query_param(query, "room") {
+            Some("new") => JoinMode::NewRoom,
+            Some(code) => JoinMode::Room(code.to_ascii_uppercase()),
+            None => JoinMode::Random,
+        }
+    };
+    let variant = if query_param(query, "variant") == Some("ultimate") {
+        Variant::Ultimate
+    } else {
+        Variant::Standard(board_size(query))
+    };
+    let time_control = query_param(query, "clock").and_then(|name| TimeControl::from_name(
+        name,
+        query_param(query, "seconds").and_then(|seconds| seconds.parse().ok()),
+    ));
 
     if is_not_socket_request(&req, &upgrade, headers, key)
     {
-        handle_http_request(&req, resources)
+        handle_http_request(&req, resources, &peer_list, &database).await
     } else {
         println!("Received a new ws handshake");
+        let account = logged_in_account(headers, &database).await;
+        if account.is_none() && !guests_allowed() {
+            return text_response(StatusCode::UNAUTHORIZED, String::from("log in to play"));
+        }
         let ver = req.version();
         tokio::task::spawn(async move {
             match hyper::upgrade::on(&mut req).await {
@@ -147,8 +222,10 @@ async fn handle_request(
                     handle_websocket(
                         WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await,
                         addr,
+                        account,
                         peer_list,
                         game_message_factory,
+                        GameRequest { join_mode, variant, time_control },
                     )
                         .await;
                 }
@@ -176,7 +253,7 @@ fn is_not_socket_request(req: &Request<Incoming>, upgrade: &HeaderValue, headers
         .get(CONNECTION)
         .and_then(|h| h.to_str().ok())
         .map(|h| {
-            h.split(|c| c == ' ' || c == ',')
+            h.split([' ', ','])
                 .any(|p| p.eq_ignore_ascii_case(upgrade.to_str().unwrap()))
         })
         .unwrap_or(false)
@@ -187,60 +264,138 @@ fn is_not_socket_request(req: &Request<Incoming>, upgrade: &HeaderValue, headers
         .unwrap_or(false)
         || !headers.get(SEC_WEBSOCKET_VERSION).map(|h| h == "13").unwrap_or(false)
         || key.is_none()
-        || req.uri() != "/socket"
+        || req.uri().path() != "/socket"
+}
+
+/// Whether players without an account may play, from the ALLOW_GUESTS
+/// variable: guests are welcome unless it is "false" or "0".
+fn guests_allowed() -> bool {
+    !matches!(env::var("ALLOW_GUESTS").as_deref(), Ok("false") | Ok("0"))
+}
+
+/// Whether login cookies are marked Secure, from the SECURE_COOKIES variable:
+/// set it to "true" or "1" when the server is reached over https.
+fn secure_cookies() -> bool {
+    matches!(env::var("SECURE_COOKIES").as_deref(), Ok("true") | Ok("1"))
+}
+
+/// Invalid messages tolerated before a client is disconnected, from the
+/// PROTOCOL_ERROR_LIMIT variable: 0 never disconnects.
+fn protocol_error_limit() -> Option<usize> {
+    let limit = env::var("PROTOCOL_ERROR_LIMIT").ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_PROTOCOL_ERROR_LIMIT);
+    (limit > 0).then_some(limit)
+}
+
+fn board_size(query: Option<&str>) -> BoardSize {
+    let dimension = |name| query_param(query, name).and_then(|value| value.parse().ok());
+    match (dimension("width"), dimension("height"), dimension("k")) {
+        (Some(width), Some(height), Some(win_length)) =>
+            BoardSize::new(width, height, win_length).unwrap_or(BoardSize::CLASSIC),
+        _ => BoardSize::CLASSIC
+    }
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|&(key, _)| key == name)
+        .map(|(_, value)| value)
 }
 
 async fn handle_websocket(
     ws_stream: WebSocketStream<TokioIo<Upgraded>>,
     addr: SocketAddr,
+    account: Option<Account>,
     peer_list: PeerList,
     game_message_factory: Arc<GameMessageFactory>,
+    request: GameRequest,
 ) {
     println!("WebSocket connection established: {}", addr);
+    let GameRequest { join_mode, variant, time_control } = request;
     let active = AtomicBool::new(true);
 
     let (outgoing, incoming) = ws_stream.split();
     let (tx, rx) = unbounded();
     let tx = Arc::new(tx);
-    let mut is_player_a = true;
-    let gs = {
-        let mut sessions = peer_list.lock().unwrap();
-        match sessions.iter()
-            .find(|s| { s.lock().unwrap().phase == GameSessionPhase::LOBBY }) {
-            Some(&ref el) => {
-                println!("Existing session found");
-                let mut session = el.lock().unwrap();
-                session.sender_b = Some(Arc::clone(&tx));
-                session.start_game(&game_message_factory);
-                is_player_a = false;
-                Arc::clone(&el)
-            }
-            None => {
-                println!("New session required");
-                let out = Arc::new(Mutex::new(GameSession::new(Arc::clone(&tx))));
-                sessions.push(Arc::clone(&out));
-                message_send(&tx, game_message_factory.get_default(GameMessageFactory::WAITING_MESSAGE));
-                out
-            }
+    let failure = match join_mode {
+        JoinMode::Resume(_) | JoinMode::Spectate(_) => GameMessageFactory::RESUME_FAILED_MESSAGE,
+        _ => GameMessageFactory::ROOM_NOT_FOUND_MESSAGE,
+    };
+    let seated = match account {
+        Some(account) => Player { name: account.name, account: Some(account.id), rating: account.rating },
+        // names are published with the games: never the peer address
+        None => Player::unrated(format!("{}{:04}", account::GUEST_PREFIX, rand::random::<u16>() % 10000)),
+    };
+    // player is None for spectators
+    let joined = match join_mode {
+        JoinMode::Spectate(target) => spectate_session(&target, &tx, &peer_list).await
+            .map(|gs| (gs, None, 0)),
+        join_mode => join_session(join_mode, variant, time_control, seated, &tx, &peer_list, &game_message_factory).await
+            .map(|(gs, player, connection_id)| (gs, Some(player), connection_id)),
+    };
+    let (gs, player, connection_id) = match joined {
+        Some(joined) => joined,
+        None => {
+            println!("No session found for {}", addr);
+            message_send(&tx, game_message_factory.get_default(failure));
+            drop(tx);
+            rx.map(|msg| msg.encode()).map(Ok).forward(outgoing).await.unwrap_or_else(|e| println!("Could not close socket: {}", e));
+            return;
         }
     };
-    let player = if is_player_a { CellOwner::PlayerA } else { CellOwner::PlayerB };
+    let error_limit = protocol_error_limit();
+    let mut protocol_errors = 0;
 
     let combined_input_output = {
         let input_processing = incoming
-            .map_ok(|msg| { game_message_factory.parse_input(&msg) })
+            .try_filter(|msg| future::ready(msg.is_text() || msg.is_binary()))
+            .map_ok(|msg| ClientMessage::decode(&msg))
             .try_for_each(|input| {
-                let mut game_session = gs.lock().unwrap();
-                game_session.process_player_input(player, input, &game_message_factory);
-                future::ok(())
+                let input = match input {
+                    Ok(input) => input,
+                    Err(error) => {
+                        println!("Invalid message from {}: {}", addr, error);
+                        protocol_errors += 1;
+                        if error_limit.is_some_and(|limit| protocol_errors >= limit) {
+                            // the END message closes the connection once sent
+                            message_send(&tx, game_message_factory.get_default(GameMessageFactory::TOO_MANY_ERRORS_MESSAGE));
+                        } else {
+                            message_send(&tx, &ServerMessage::Error(error.to_string()));
+                        }
+                        return future::ok(());
+                    }
+                };
+                if error_limit.is_some_and(|limit| protocol_errors >= limit) {
+                    return future::ok(());
+                }
+                let delivered = match player {
+                    Some(player) => gs.send(SessionCommand::Input { player, message: input }),
+                    None if matches!(input, ClientMessage::ClientClick { .. }) => {
+                        message_send(&tx, game_message_factory.get_default(GameMessageFactory::SPECTATOR_INPUT_MESSAGE));
+                        true
+                    }
+                    None if matches!(input, ClientMessage::Chat { .. }) => {
+                        message_send(&tx, game_message_factory.get_default(GameMessageFactory::SPECTATOR_CHAT_MESSAGE));
+                        true
+                    }
+                    None if input == ClientMessage::GetState => gs.send(SessionCommand::SpectatorState { sender: Arc::clone(&tx) }),
+                    None => true,
+                };
+                if delivered {
+                    future::ok(())
+                } else {
+                    future::err(tungstenite::Error::ConnectionClosed)
+                }
             });
 
         let output_stream = rx
             .map(|msg| {
-                if game_message_factory.parse_input(&msg).1 == MessageType::END {
+                if matches!(msg, ServerMessage::End(_)) {
                     active.store(false, Ordering::Relaxed);
                 }
-                msg
+                msg.encode()
             })
             .take_until(future::poll_fn(|_| {
                 if active.load(Ordering::Relaxed) {
@@ -253,16 +408,348 @@ async fn handle_websocket(
         future::select(output_stream, input_processing)
     };
 
-    combined_input_output.await;
+    // a crashed session ends the connection instead of leaving it hanging
+    tokio::select! {
+        _ = combined_input_output => (),
+        _ = gs.stopped() => println!("Session {} stopped under {}", gs.id, addr),
+    }
 
     println!("{} disconnected", &addr);
 
-    let mut gs = gs.lock().unwrap();
-    gs.close_session(player, &game_message_factory);
+    let kicked = error_limit.is_some_and(|limit| protocol_errors >= limit);
+    gs.send(match player {
+        // no seat is kept for a player disconnected for sending junk
+        Some(player) if kicked => SessionCommand::Kick { player },
+        Some(player) => SessionCommand::Leave { player, connection_id },
+        None => SessionCommand::Unspectate { sender: tx },
+    });
+}
+
+/// The id in paths such as /games/{id}/replay.
+fn id_between<T: FromStr>(path: &str, prefix: &str, suffix: &str) -> Option<T> {
+    path.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok()
+}
+
+/// Checks a game in text notation, answering with the reconstructed board.
+async fn import_notation(req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+    let body = match Limited::new(req.into_body(), MAX_NOTATION_BYTES).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e.to_string()}).to_string()),
+    };
+    let imported = Notation::parse(&String::from_utf8_lossy(&body))
+        .and_then(|notation| notation.replay().map(|board| (notation, board)));
+    match imported {
+        Ok((notation, board)) => {
+            let cells: Vec<&str> = board.cells().into_iter().map(|owner| match owner {
+                CellOwner::PlayerA => "x-cell",
+                CellOwner::PlayerB => "o-cell",
+                _ => "empty-cell",
+            }).collect();
+            json_response(StatusCode::OK, serde_json::json!({
+                "variant": notation.variant.to_string(),
+                "player_x": notation.player_x,
+                "player_o": notation.player_o,
+                "date": notation.date,
+                "result": notation.result,
+                "termination": notation.termination,
+                "moves": notation.moves,
+                "cells": cells,
+            }).to_string())
+        }
+        Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e.to_string()}).to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+/// Registration or login from a JSON name and password, answering with the
+/// account and a login cookie.
+async fn handle_credentials(req: Request<Incoming>, database: Arc<GameDatabase>) -> Result<Response<Body>, Infallible> {
+    let register = req.uri().path() == "/account/register";
+    let credentials = match Limited::new(req.into_body(), MAX_CREDENTIALS_BYTES).collect().await {
+        Ok(body) => serde_json::from_slice::<Credentials>(&body.to_bytes()).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    let credentials = match credentials {
+        Ok(credentials) => credentials,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e}).to_string()),
+    };
+    // password hashing takes a while on purpose
+    let logged_in = tokio::task::spawn_blocking(move || if register {
+        account::register(&database, &credentials.name, &credentials.password)
+    } else {
+        account::login(&database, &credentials.name, &credentials.password)
+    }).await.unwrap_or_else(|e| Err(AccountError::Storage(e.to_string())));
+    match logged_in {
+        Ok((account, token)) => with_login_cookie(
+            json_response(StatusCode::OK, serde_json::to_string(&account).unwrap()),
+            &token,
+        ),
+        Err(e) => {
+            let status = match e {
+                AccountError::InvalidName | AccountError::ReservedName | AccountError::InvalidPassword => StatusCode::BAD_REQUEST,
+                AccountError::NameTaken => StatusCode::CONFLICT,
+                AccountError::WrongCredentials => StatusCode::UNAUTHORIZED,
+                AccountError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            json_response(status, serde_json::json!({"error": e.to_string()}).to_string())
+        }
+    }
+}
+
+fn with_login_cookie(response: Result<Response<Body>, Infallible>, token: &str) -> Result<Response<Body>, Infallible> {
+    response.map(|mut res| {
+        res.headers_mut().append(SET_COOKIE, account::login_cookie(token, secure_cookies()).parse().unwrap());
+        res
+    })
+}
+
+/// The account behind the login cookie, None for guests.
+async fn logged_in_account(headers: &HeaderMap, database: &Arc<GameDatabase>) -> Option<Account> {
+    let token = String::from(account::login_token(headers)?);
+    query(database, move |database| database.account_of_login(&token)).await.unwrap_or_else(|e| {
+        println!("Could not check login: {}", e);
+        None
+    })
+}
+
+/// Runs a query on the blocking pool, sqlite calls would stall the async workers.
+async fn query<T: Send + 'static>(
+    database: &Arc<GameDatabase>,
+    query: impl FnOnce(&GameDatabase) -> T + Send + 'static,
+) -> T {
+    let database = Arc::clone(database);
+    tokio::task::spawn_blocking(move || query(&database)).await.expect("database query panicked")
+}
+
+fn text_response(status: StatusCode, body: String) -> Result<Response<Body>, Infallible> {
+    let mut res = Response::new(Body::from(body));
+    *res.status_mut() = status;
+    res.headers_mut().append(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+    Ok(res)
+}
+
+fn json_response(status: StatusCode, body: String) -> Result<Response<Body>, Infallible> {
+    let mut res = Response::new(Body::from(body));
+    *res.status_mut() = status;
+    res.headers_mut().append(CONTENT_TYPE, "application/json".parse().unwrap());
+    Ok(res)
+}
+
+fn database_error(error: rusqlite::Error) -> Result<Response<Body>, Infallible> {
+    println!("Database error: {}", error);
+    json_response(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({"error": "database error"}).to_string())
+}
+
+/// Public games being played right now, as a JSON list for spectators to pick from.
+fn live_games(peer_list: &PeerList) -> String {
+    let sessions = peer_list.lock().unwrap();
+    let mut games: Vec<(u64, String)> = sessions.sessions()
+        .filter_map(|s| {
+            let status = s.status();
+            (status.phase == GameSessionPhase::PLAYING && status.room.is_none())
+                .then(|| (s.id, status.kind.0.to_string()))
+        })
+        .collect();
+    games.sort_unstable();
+    let games: Vec<serde_json::Value> = games.into_iter()
+        .map(|(id, variant)| serde_json::json!({"id": id, "variant": variant}))
+        .collect();
+    serde_json::Value::from(games).to_string()
+}
+
+/// The notation of a game still being played.
+async fn live_notation(id: u64, peer_list: &PeerList) -> Result<Response<Body>, Infallible> {
+    let session = peer_list.lock().unwrap().get(id).cloned();
+    let notation = match session {
+        Some(session) => session.request(|reply| SessionCommand::Notation { reply }).await,
+        None => None,
+    };
+    match notation {
+        Some(notation) => text_response(StatusCode::OK, notation.to_string()),
+        None => text_response(StatusCode::NOT_FOUND, String::from("game not found")),
+    }
+}
+
+/// Attaches a spectator to a live session, looked up by id or room code.
+async fn spectate_session(
+    target: &str,
+    tx: &Arc<UnboundedSender<ServerMessage>>,
+    peer_list: &PeerList,
+) -> Option<SessionHandle> {
+    let gs = {
+        let sessions = peer_list.lock().unwrap();
+        match target.parse() {
+            Ok(id) => sessions.get(id),
+            Err(_) => sessions.room(target),
+        }?.clone()
+    };
+    let sender = Arc::clone(tx);
+    gs.request(|reply| SessionCommand::Spectate { sender, reply }).await?.then_some(gs)
+}
+
+/// Seats the player in a session, None when the requested room or game does not exist.
+/// Answers the seat taken and the id of the connection holding it.
+async fn join_session(
+    join_mode: JoinMode,
+    variant: Variant,
+    time_control: Option<TimeControl>,
+    seated: Player,
+    tx: &Arc<UnboundedSender<ServerMessage>>,
+    peer_list: &PeerList,
+    game_message_factory: &Arc<GameMessageFactory>,
+) -> Option<(SessionHandle, CellOwner, u32)> {
+    if let JoinMode::Resume(token) = join_mode {
+        let gs = peer_list.lock().unwrap().get(GameSession::session_of_token(&token)?)?.clone();
+        let sender = Arc::clone(tx);
+        let (player, connection_id) = gs.request(|reply| SessionCommand::Resume { token, sender, reply }).await??;
+        return Some((gs, player, connection_id));
+    }
+    loop {
+        let lobby = {
+            let mut sessions = peer_list.lock().unwrap();
+            match &join_mode {
+                JoinMode::Random => find_lobby(&sessions.waiting(&(variant, time_control)), seated.rating).cloned(),
+                JoinMode::Room(code) => Some(sessions.room(code)?.clone()),
+                _ => None
+            }
+        };
+        let Some(lobby) = lobby else { break };
+        let (sender, player) = (Arc::clone(tx), seated.clone());
+        if lobby.request(|reply| SessionCommand::Join { sender, player, reply }).await == Some(true) {
+            println!("Existing session found");
+            return Some((lobby, CellOwner::PlayerB, 0));
+        }
+        // the lobby was taken in the meantime: another one may do, a room will not
+        if matches!(join_mode, JoinMode::Room(_)) {
+            return None;
+        }
+    }
+    message_send(tx, &ServerMessage::Board(variant.to_string()));
+    let mut session = GameSession::new(Arc::clone(tx), variant, time_control);
+    session.set_player(CellOwner::PlayerA, seated);
+    let token = session.issue_resume_token(CellOwner::PlayerA);
+    message_send(tx, &ServerMessage::Token(token));
+    let mut sessions = peer_list.lock().unwrap();
+    match join_mode {
+        JoinMode::Computer(difficulty) => {
+            println!("New session against the computer");
+            let (bot_tx, bot_rx) = unbounded();
+            session.sender_b = Some(Arc::new(bot_tx));
+            session.set_player(CellOwner::PlayerB, Player::unrated(format!("computer ({})", difficulty.name())));
+            session.start_game(game_message_factory);
+            let out = sessions.insert(session, Arc::clone(game_message_factory));
+            spawn_bot(out.clone(), bot_rx, difficulty);
+            return Some((out, CellOwner::PlayerA, 0));
+        }
+        JoinMode::NewRoom => {
+            let code = new_room_code(&sessions);
+            println!("New private room {}", code);
+            message_send(tx, &ServerMessage::Room(code.clone()));
+            session.room = Some(code);
+        }
+        _ => println!("New session required")
+    }
+    session.send_state(tx, Some(CellOwner::PlayerA));
+    message_send(tx, game_message_factory.get_default(GameMessageFactory::WAITING_MESSAGE));
+    Some((sessions.insert(session, Arc::clone(game_message_factory)), CellOwner::PlayerA, 0))
+}
+
+fn new_room_code(sessions: &SessionRegistry) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let code: String = (0..ROOM_CODE_LENGTH)
+            .map(|_| *ROOM_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+            .collect();
+        if sessions.room(&code).is_none() {
+            return code;
+        }
+    }
 }
 
-fn handle_http_request(req: &Request<Incoming>, resources: &'static StaticResource) -> Result<Response<Body>, Infallible> {
+async fn handle_http_request(
+    req: &Request<Incoming>,
+    resources: &'static StaticResource,
+    peer_list: &PeerList,
+    database: &Arc<GameDatabase>,
+) -> Result<Response<Body>, Infallible> {
     match req.uri().path() {
+        "/account" => json_response(StatusCode::OK, serde_json::json!({
+            "account": logged_in_account(req.headers(), database).await,
+            "guests_allowed": guests_allowed(),
+        }).to_string()),
+        "/account/logout" if req.method() == Method::POST => {
+            if let Some(token) = account::login_token(req.headers()).map(String::from) {
+                query(database, move |database| database.delete_login(&token)).await
+                    .unwrap_or_else(|e| println!("Could not log out: {}", e));
+            }
+            with_login_cookie(json_response(StatusCode::OK, String::from("{}")), "")
+        },
+        "/games" => json_response(StatusCode::OK, live_games(peer_list)),
+        "/games/recent" => match query(database, |database| database.recent_games(RECENT_GAMES_LIMIT)).await {
+            Ok(games) => json_response(StatusCode::OK, serde_json::to_string(&games).unwrap()),
+            Err(e) => database_error(e),
+        },
+        "/players/top" => {
+            let limit = query_param(req.uri().query(), "limit")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(LEADERBOARD_LIMIT)
+                .min(MAX_LEADERBOARD_LIMIT);
+            match query(database, move |database| database.top_players(limit)).await {
+                Ok(players) => json_response(StatusCode::OK, serde_json::to_string(&players).unwrap()),
+                Err(e) => database_error(e),
+            }
+        },
+        path if id_between::<String>(path, "/players/", "/stats").is_some() => {
+            let name = id_between::<String>(path, "/players/", "/stats").unwrap();
+            match query(database, move |database| database.player_stats(&name)).await {
+                Ok(Some(stats)) => json_response(StatusCode::OK, serde_json::to_string(&stats).unwrap()),
+                Ok(None) => json_response(StatusCode::NOT_FOUND, serde_json::json!({"error": "player not found"}).to_string()),
+                Err(e) => database_error(e),
+            }
+        },
+        "/leaderboard" => Ok(Response::new(Body::from(&resources.leaderboard_page[..]))),
+        "/leaderboard.js" => {
+            let mut res = Response::new(Body::from(&resources.leaderboard_javascript[..]));
+            *res.status_mut() = StatusCode::OK;
+            res.headers_mut().append(CONTENT_TYPE, "application/javascript".parse().unwrap());
+            Ok(res)
+        },
+        path if id_between::<i64>(path, "/games/", "/replay").is_some() => {
+            let id = id_between(path, "/games/", "/replay").unwrap();
+            match query(database, move |database| database.replay(id)).await {
+                Ok(Some(replay)) => json_response(StatusCode::OK, serde_json::to_string(&replay).unwrap()),
+                Ok(None) => json_response(StatusCode::NOT_FOUND, serde_json::json!({"error": "game not found"}).to_string()),
+                Err(e) => database_error(e),
+            }
+        },
+        path if id_between::<i64>(path, "/games/", "/notation").is_some() => {
+            let id = id_between(path, "/games/", "/notation").unwrap();
+            match query(database, move |database| database.replay(id)).await {
+                Ok(Some(replay)) => text_response(StatusCode::OK, Notation {
+                    variant: Variant::parse(&replay.game.variant).unwrap_or(Variant::Standard(BoardSize::CLASSIC)),
+                    date: Notation::date_of(replay.game.started_at),
+                    result: String::from(Notation::result_of(replay.game.winner.as_deref())),
+                    termination: Notation::termination_of(&replay.game.result),
+                    moves: replay.moves.into_iter().map(|played| played.cell).collect(),
+                    player_x: replay.game.player_x,
+                    player_o: replay.game.player_o,
+                }.to_string()),
+                Ok(None) => text_response(StatusCode::NOT_FOUND, String::from("game not found")),
+                Err(e) => database_error(e),
+            }
+        },
+        path if path.starts_with("/replay/") => Ok(Response::new(Body::from(&resources.replay_page[..]))),
+        "/replay.js" => {
+            let mut res = Response::new(Body::from(&resources.replay_javascript[..]));
+            *res.status_mut() = StatusCode::OK;
+            res.headers_mut().append(CONTENT_TYPE, "application/javascript".parse().unwrap());
+            Ok(res)
+        },
         "/app.js" => {
             let mut res = Response::new(Body::from(&resources.javascript[..]));
             *res.status_mut() = StatusCode::OK;
@@ -0,0 +1,112 @@
+use crate::actor::{SessionCommand, SessionHandle};
+use crate::session::SessionStatus;
+use crate::PeerList;
+use std::time::Duration;
+
+const MATCHMAKING_TICK: Duration = Duration::from_secs(1);
+/// Largest rating gap accepted right away.
+const INITIAL_WINDOW: f64 = 100.0;
+const WINDOW_GROWTH_PER_SECOND: f64 = 10.0;
+/// Past this wait anyone will do, a game beats an empty lobby.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Largest rating gap accepted by a player who waited this long.
+pub fn rating_window(waited: Duration) -> f64 {
+    if waited >= MAX_WAIT {
+        f64::INFINITY
+    } else {
+        INITIAL_WINDOW + WINDOW_GROWTH_PER_SECOND * waited.as_secs_f64()
+    }
+}
+
+/// Lobby with the closest rating the new player can join, if any.
+pub fn find_lobby(lobbies: &[SessionHandle], rating: f64) -> Option<&SessionHandle> {
+    lobbies.iter()
+        .filter_map(|handle| {
+            let (waiting_rating, waited) = handle.status().waiting_rating()?;
+            let gap = (waiting_rating - rating).abs();
+            (gap <= rating_window(waited)).then_some((handle, gap))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(handle, _)| handle)
+}
+
+/// Pairs players left waiting in separate lobbies once the window of the
+/// longest waiting one grows wide enough to accept the other.
+pub fn spawn_matchmaking(peer_list: PeerList) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(MATCHMAKING_TICK);
+        loop {
+            interval.tick().await;
+            let mut sessions = peer_list.lock().unwrap();
+            for kind in sessions.lobby_kinds() {
+                // oldest lobbies come first in the queue and host the game
+                let lobbies = sessions.waiting(&kind);
+                let statuses: Vec<SessionStatus> = lobbies.iter().map(SessionHandle::status).collect();
+                let mut paired = vec![false; lobbies.len()];
+                for host in 0..lobbies.len() {
+                    for guest in host + 1..lobbies.len() {
+                        if paired[host] || paired[guest] || !compatible(&statuses[host], &statuses[guest]) {
+                            continue;
+                        }
+                        paired[host] = true;
+                        paired[guest] = true;
+                        println!("Matchmaking pairs session {} with session {}", lobbies[host].id, lobbies[guest].id);
+                        lobbies[guest].send(SessionCommand::HandOver { to: lobbies[host].clone() });
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Both lobbies play the same kind of game, only ratings are left to compare.
+fn compatible(host: &SessionStatus, guest: &SessionStatus) -> bool {
+    match (host.waiting_rating(), guest.waiting_rating()) {
+        (Some((host_rating, host_waited)), Some((guest_rating, guest_waited))) =>
+            (host_rating - guest_rating).abs() <= rating_window(host_waited.max(guest_waited)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::spawn_session;
+    use crate::board::{BoardSize, CellOwner};
+    use crate::message::GameMessageFactory;
+    use crate::session::{GameSession, Player};
+    use crate::variant::Variant;
+    use futures_channel::mpsc::unbounded;
+    use std::sync::Arc;
+
+    fn lobby(rating: f64, room: Option<&str>) -> SessionHandle {
+        let (sender, _) = unbounded();
+        let mut session = GameSession::new(Arc::new(sender), Variant::Standard(BoardSize::CLASSIC), None);
+        session.set_player(CellOwner::PlayerA, Player { name: String::from("alice"), account: Some(1), rating });
+        session.room = room.map(String::from);
+        let (events, _) = unbounded();
+        spawn_session(session, Arc::new(GameMessageFactory::new()), events)
+    }
+
+    #[test]
+    fn window_widens_while_waiting() {
+        assert_eq!(rating_window(Duration::ZERO), 100.0);
+        assert_eq!(rating_window(Duration::from_secs(1)), 110.0);
+        assert_eq!(rating_window(Duration::from_millis(30_500)), 405.0);
+        assert_eq!(rating_window(Duration::from_secs(59)), 690.0);
+        assert_eq!(rating_window(Duration::from_secs(60)), f64::INFINITY);
+        assert_eq!(rating_window(Duration::from_secs(3600)), f64::INFINITY);
+    }
+
+    #[tokio::test]
+    async fn closest_rating_within_the_window_is_joined() {
+        let lobbies = [lobby(1560.0, None), lobby(1700.0, None), lobby(1450.0, None), lobby(1500.0, Some("ROOM"))];
+        assert_eq!(find_lobby(&lobbies, 1500.0).map(|handle| handle.id), Some(lobbies[2].id));
+        assert_eq!(find_lobby(&lobbies, 1620.0).map(|handle| handle.id), Some(lobbies[0].id));
+        // private rooms are never paired, far ratings not right away
+        assert!(find_lobby(&lobbies[1..2], 1500.0).is_none());
+        assert!(find_lobby(&lobbies[3..], 1500.0).is_none());
+        assert!(find_lobby(&[], 1500.0).is_none());
+    }
+}
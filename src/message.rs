@@ -1,22 +1,157 @@
+use crate::database::GameResult;
+use crate::session::GameSessionPhase;
 use futures_channel::mpsc::{TrySendError, UnboundedSender};
-use serde_json::{Value, Value::String as JsonString};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 use tokio_tungstenite::tungstenite::protocol::Message;
 
-#[non_exhaustive]
-pub struct MessageType;
+/// Sent as "v" in every message, bumped on breaking protocol changes.
+pub const PROTOCOL_VERSION: u32 = 1;
 
-impl MessageType {
-    pub const CLIENT_CLICK: &'static str = "CLIENT_CLICK";
-    pub const SHOW: &'static str = "SHOW";
-    pub const INFO: &'static str = "INFO";
-    pub const ERROR: &'static str = "ERROR";
-    pub const FIGURE: &'static str = "FIGURE";
-    pub const END: &'static str = "END";
+/// Messages from the client, encoded as {"v": 1, "type": "...", ...}.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClientMessage {
+    /// A move, in the text encoding of the game variant.
+    ClientClick { text: String },
+    Rematch,
+    /// Asks for a STATE message.
+    GetState,
+    /// Asks the opponent to undo the last own move, along with their reply if any.
+    Takeback,
+    AcceptTakeback,
+    DeclineTakeback,
+    /// Gives the game up, the opponent wins.
+    Resign,
+    /// Offers a tie, along with the reply of the opponent. Offering back
+    /// accepts the pending offer.
+    OfferDraw,
+    AcceptDraw,
+    DeclineDraw,
+    /// Free text for the opponent and the spectators.
+    Chat { text: String },
+}
+
+/// Messages to the client, encoded as {"v": 1, "type": "...", "text": "..."}.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", content = "text", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerMessage {
+    /// The last played move, drawn with the current figure.
+    Show(String),
+    Info(String),
+    Error(String),
+    Figure(String),
+    /// The game is over and the connection will be closed.
+    End(String),
+    /// The game variant, the client has to build an empty board for it.
+    Board(String),
+    /// Small boards the next move may go to, for ultimate games.
+    Active(String),
+    Room(String),
+    Token(String),
+    /// The game is over, players may ask for a rematch: text is an object.
+    Result(GameOver),
+    Rematch(String),
+    /// The opponent asks to undo their last move, to be answered with
+    /// ACCEPT_TAKEBACK or DECLINE_TAKEBACK.
+    Takeback(String),
+    /// The opponent offers a tie, to be answered with ACCEPT_DRAW or DECLINE_DRAW.
+    DrawOffer(String),
+    /// A chat message prefixed by the figure of its author, html escaped.
+    Chat(String),
+    /// Wins, losses and ties.
+    Score(String),
+    /// Seconds left, own clock first.
+    Clock(String),
+    /// Cells of every line completed by the winner, as moves in the text
+    /// encoding of the game variant: text is a list of lines.
+    Lines(Vec<Vec<String>>),
+    /// The whole game, for clients to redraw from scratch: text is an object.
+    State(GameState),
+}
+
+/// How the game ended, for clients to tell apart without parsing the
+/// message, which is only meant to be displayed.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct GameOver {
+    pub reason: GameResult,
+    pub message: String,
+}
+
+/// Snapshot of a game as seen by one client.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct GameState {
+    pub variant: String,
+    /// Figure in every cell ("x-cell", "o-cell" or "empty-cell"), in the
+    /// order of the client grid.
+    pub cells: Vec<String>,
+    /// Figure of the player to move.
+    pub turn: String,
+    /// Own figure, None for spectators.
+    pub figure: Option<String>,
+    pub phase: GameSessionPhase,
+    pub move_count: usize,
+    /// Small boards the next move may go to, for ultimate games.
+    pub active_boards: Option<Vec<usize>>,
+    /// Lines completed by the winner, as in the LINES message.
+    pub winning_lines: Vec<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Envelope<T> {
+    v: u32,
+    #[serde(flatten)]
+    message: T,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    NotText,
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::NotText => write!(f, "expected a text message"),
+            DecodeError::Json(error) => write!(f, "invalid message: {}", error),
+            DecodeError::UnsupportedVersion(version) =>
+                write!(f, "unsupported protocol version {}, expected {}", version, PROTOCOL_VERSION),
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn decode(input: &Message) -> Result<ClientMessage, DecodeError> {
+        let Message::Text(text) = input else {
+            return Err(DecodeError::NotText);
+        };
+        println!("Received a message: {}", text);
+        let envelope: Envelope<serde_json::Value> = serde_json::from_str(text).map_err(DecodeError::Json)?;
+        if envelope.v != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(envelope.v));
+        }
+        serde_json::from_value(envelope.message).map_err(DecodeError::Json)
+    }
+}
+
+impl ServerMessage {
+    pub fn encode(&self) -> Message {
+        let message = serde_json::to_string(&Envelope { v: PROTOCOL_VERSION, message: self })
+            .expect("Server messages always serialize");
+        println!("The resulting message is: {message}");
+        Message::Text(message)
+    }
+}
+
+fn game_over(reason: GameResult, message: String) -> ServerMessage {
+    ServerMessage::Result(GameOver { reason, message })
 }
 
 pub struct GameMessageFactory {
-    defaults: HashMap<usize, String>,
+    defaults: HashMap<usize, ServerMessage>,
 }
 
 impl GameMessageFactory {
@@ -29,18 +164,80 @@ impl GameMessageFactory {
     pub const WITHDRAWAL_MESSAGE: usize = 6;
     pub const X_FIGURE_MESSAGE: usize = 7;
     pub const O_FIGURE_MESSAGE: usize = 8;
+    pub const ROOM_NOT_FOUND_MESSAGE: usize = 9;
+    pub const RESUME_FAILED_MESSAGE: usize = 10;
+    pub const OPPONENT_AWAY_MESSAGE: usize = 11;
+    pub const X_TURN_MESSAGE: usize = 12;
+    pub const O_TURN_MESSAGE: usize = 13;
+    pub const X_WON_MESSAGE: usize = 14;
+    pub const O_WON_MESSAGE: usize = 15;
+    pub const PLAYER_LEFT_MESSAGE: usize = 16;
+    pub const SPECTATOR_INPUT_MESSAGE: usize = 17;
+    pub const PLAYER_AWAY_MESSAGE: usize = 18;
+    pub const SPECTATOR_TIE_MESSAGE: usize = 19;
+    pub const REMATCH_OFFER_MESSAGE: usize = 20;
+    pub const REMATCH_WAITING_MESSAGE: usize = 21;
+    pub const TIME_OUT_LOST_MESSAGE: usize = 22;
+    pub const TIME_OUT_WIN_MESSAGE: usize = 23;
+    pub const TOO_MANY_ERRORS_MESSAGE: usize = 24;
+    pub const TAKEBACK_OFFER_MESSAGE: usize = 25;
+    pub const TAKEBACK_WAITING_MESSAGE: usize = 26;
+    pub const TAKEBACK_DECLINED_MESSAGE: usize = 27;
+    pub const RESIGNED_MESSAGE: usize = 28;
+    pub const OPPONENT_RESIGNED_MESSAGE: usize = 29;
+    pub const X_RESIGNED_MESSAGE: usize = 30;
+    pub const O_RESIGNED_MESSAGE: usize = 31;
+    pub const DRAW_OFFER_MESSAGE: usize = 32;
+    pub const DRAW_WAITING_MESSAGE: usize = 33;
+    pub const DRAW_DECLINED_MESSAGE: usize = 34;
+    pub const DRAW_AGREED_MESSAGE: usize = 35;
+    pub const SPECTATOR_DRAW_AGREED_MESSAGE: usize = 36;
+    pub const OPPONENT_WITHDREW_MESSAGE: usize = 37;
+    pub const SPECTATOR_WITHDRAWAL_MESSAGE: usize = 38;
+    pub const SPECTATOR_CHAT_MESSAGE: usize = 39;
 
     pub fn new() -> GameMessageFactory {
         let defaults = HashMap::from([
-            (Self::YOUR_TURN_MESSAGE, Self::build_plain_message("Your turn!", MessageType::INFO)),
-            (Self::OPPONENT_TURN_MESSAGE, Self::build_plain_message("Opponent turn!", MessageType::INFO)),
-            (Self::WAITING_MESSAGE, Self::build_plain_message("Waiting for opponent", MessageType::INFO)),
-            (Self::LOST_MESSAGE, Self::build_plain_message("You lost!<br><br>Tap here to play again!", MessageType::END)),
-            (Self::WIN_MESSAGE, Self::build_plain_message("You won!<br><br>Tap here to play again!", MessageType::END)),
-            (Self::TIE_MESSAGE, Self::build_plain_message("Tie!<br><br>Tap here to play again!", MessageType::END)),
-            (Self::WITHDRAWAL_MESSAGE, Self::build_plain_message("Your opponent left the game!<br><br>Tap here to play again!", MessageType::END)),
-            (Self::X_FIGURE_MESSAGE, Self::build_plain_message("x-cell", MessageType::FIGURE)),
-            (Self::O_FIGURE_MESSAGE, Self::build_plain_message("o-cell", MessageType::FIGURE))
+            (Self::YOUR_TURN_MESSAGE, ServerMessage::Info(String::from("Your turn!"))),
+            (Self::OPPONENT_TURN_MESSAGE, ServerMessage::Info(String::from("Opponent turn!"))),
+            (Self::WAITING_MESSAGE, ServerMessage::Info(String::from("Waiting for opponent"))),
+            (Self::LOST_MESSAGE, game_over(GameResult::Line, String::from("You lost!<br><br>Tap here for a rematch!"))),
+            (Self::WIN_MESSAGE, game_over(GameResult::Line, String::from("You won!<br><br>Tap here for a rematch!"))),
+            (Self::TIE_MESSAGE, game_over(GameResult::Tie, String::from("Tie!<br><br>Tap here for a rematch!"))),
+            (Self::WITHDRAWAL_MESSAGE, ServerMessage::End(String::from("Your opponent left the game!<br><br>Tap here to play again!"))),
+            (Self::X_FIGURE_MESSAGE, ServerMessage::Figure(String::from("x-cell"))),
+            (Self::O_FIGURE_MESSAGE, ServerMessage::Figure(String::from("o-cell"))),
+            (Self::ROOM_NOT_FOUND_MESSAGE, ServerMessage::End(String::from("This room does not exist or is already full!<br><br>Tap here to play again!"))),
+            (Self::RESUME_FAILED_MESSAGE, ServerMessage::End(String::from("This game is over!<br><br>Tap here to play again!"))),
+            (Self::OPPONENT_AWAY_MESSAGE, ServerMessage::Info(String::from("Opponent connection lost, waiting for them to come back..."))),
+            (Self::X_TURN_MESSAGE, ServerMessage::Info(String::from("X to move"))),
+            (Self::O_TURN_MESSAGE, ServerMessage::Info(String::from("O to move"))),
+            (Self::X_WON_MESSAGE, game_over(GameResult::Line, String::from("X won!"))),
+            (Self::O_WON_MESSAGE, game_over(GameResult::Line, String::from("O won!"))),
+            (Self::PLAYER_LEFT_MESSAGE, ServerMessage::End(String::from("A player left the game!<br><br>Tap here to play again!"))),
+            (Self::SPECTATOR_INPUT_MESSAGE, ServerMessage::Error(String::from("Spectators cannot play"))),
+            (Self::PLAYER_AWAY_MESSAGE, ServerMessage::Info(String::from("A player lost the connection, waiting for them to come back..."))),
+            (Self::SPECTATOR_TIE_MESSAGE, game_over(GameResult::Tie, String::from("Tie!"))),
+            (Self::REMATCH_OFFER_MESSAGE, ServerMessage::Rematch(String::from("Your opponent wants a rematch!<br><br>Tap here to accept!"))),
+            (Self::REMATCH_WAITING_MESSAGE, ServerMessage::Info(String::from("Waiting for your opponent to accept the rematch..."))),
+            (Self::TIME_OUT_LOST_MESSAGE, game_over(GameResult::Timeout, String::from("Time is up, you lost!<br><br>Tap here for a rematch!"))),
+            (Self::TIME_OUT_WIN_MESSAGE, game_over(GameResult::Timeout, String::from("Your opponent ran out of time, you won!<br><br>Tap here for a rematch!"))),
+            (Self::TOO_MANY_ERRORS_MESSAGE, ServerMessage::End(String::from("Too many invalid messages, disconnected!<br><br>Tap here to play again!"))),
+            (Self::TAKEBACK_OFFER_MESSAGE, ServerMessage::Takeback(String::from("Your opponent asks to take back their last move"))),
+            (Self::TAKEBACK_WAITING_MESSAGE, ServerMessage::Info(String::from("Waiting for your opponent to accept the takeback..."))),
+            (Self::TAKEBACK_DECLINED_MESSAGE, ServerMessage::Info(String::from("Your opponent declined the takeback"))),
+            (Self::RESIGNED_MESSAGE, game_over(GameResult::Resignation, String::from("You resigned!<br><br>Tap here for a rematch!"))),
+            (Self::OPPONENT_RESIGNED_MESSAGE, game_over(GameResult::Resignation, String::from("Your opponent resigned, you won!<br><br>Tap here for a rematch!"))),
+            (Self::X_RESIGNED_MESSAGE, game_over(GameResult::Resignation, String::from("X resigned, O won!"))),
+            (Self::O_RESIGNED_MESSAGE, game_over(GameResult::Resignation, String::from("O resigned, X won!"))),
+            (Self::DRAW_OFFER_MESSAGE, ServerMessage::DrawOffer(String::from("Your opponent offers a draw"))),
+            (Self::DRAW_WAITING_MESSAGE, ServerMessage::Info(String::from("Waiting for your opponent to accept the draw..."))),
+            (Self::DRAW_DECLINED_MESSAGE, ServerMessage::Info(String::from("Your opponent declined the draw"))),
+            (Self::DRAW_AGREED_MESSAGE, game_over(GameResult::AgreedDraw, String::from("Draw agreed!<br><br>Tap here for a rematch!"))),
+            (Self::SPECTATOR_DRAW_AGREED_MESSAGE, game_over(GameResult::AgreedDraw, String::from("Draw agreed!"))),
+            (Self::OPPONENT_WITHDREW_MESSAGE, game_over(GameResult::Withdrawal, String::from("Your opponent left, you won!"))),
+            (Self::SPECTATOR_WITHDRAWAL_MESSAGE, game_over(GameResult::Withdrawal, String::from("A player left, the game is over!"))),
+            (Self::SPECTATOR_CHAT_MESSAGE, ServerMessage::Error(String::from("Spectators cannot chat")))
         ]);
 
         GameMessageFactory {
@@ -48,61 +245,95 @@ impl GameMessageFactory {
         }
     }
 
-    pub fn get_default(&self, index: usize) -> &String {
+    pub fn get_default(&self, index: usize) -> &ServerMessage {
         match self.defaults.get(&index) {
             Some(value) => value,
             None => panic!("No default value found for index {}", index)
         }
     }
+}
 
-    pub fn parse_input(&self, input: &Message) -> (String, String) {
-        let input_text = input.to_text().unwrap();
-        println!("Received a message: {}", input_text);
-        let parsed: Result<Value, _> = serde_json::from_str(input_text);
-        match parsed {
-            Ok(json) => {
-                let input_text = match json.get("text").unwrap() {
-                    JsonString(str) => String::from(str),
-                    _ => panic!("Invalid text from client")
-                };
-                let input_type = match json.get("type").unwrap() {
-                    JsonString(str) => String::from(str),
-                    _ => panic!("Invalid type from client")
-                };
-                (input_text, input_type)
-            }
-            Err(_) => (String::from("Unexpected input"), String::from(MessageType::ERROR))
-        }
+pub fn multi_message_send(sender: &UnboundedSender<ServerMessage>, messages: &[&ServerMessage]) {
+    for &message in messages {
+        message_send(sender, message);
     }
+}
 
-    pub fn build_plain_message(m: &str, t: &str) -> String {
-        let message =
-            serde_json::json!(
-                {
-                    "text": m,
-                    "type": t
-                }
-            ).to_string();
+pub fn message_send(sender: &UnboundedSender<ServerMessage>, message: &ServerMessage) {
+    sender.unbounded_send(message.clone()).unwrap_or_else(sent_fail_notify);
+}
 
-        println!("The resulting message is: {message}");
-        message
-    }
+fn sent_fail_notify(_: TrySendError<ServerMessage>) {
+    println!("Could not send message.")
 }
 
-pub fn multi_message_send(sender: &UnboundedSender<Message>, plain_messages: &[&String]) {
-    for &plain_message in plain_messages {
-        message_send(sender, plain_message);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(text: &str) -> Result<ClientMessage, DecodeError> {
+        ClientMessage::decode(&Message::Text(String::from(text)))
     }
-}
 
-pub fn message_send(sender: &UnboundedSender<Message>, plain_message: &String) {
-    sender.unbounded_send(ws_message_of(plain_message)).unwrap_or_else(sent_fail_notify);
-}
+    #[test]
+    fn every_client_message_decodes() {
+        let frames = [
+            (r#"{"v":1,"type":"CLIENT_CLICK","text":"4"}"#, ClientMessage::ClientClick { text: String::from("4") }),
+            (r#"{"v":1,"type":"REMATCH"}"#, ClientMessage::Rematch),
+            (r#"{"v":1,"type":"GET_STATE"}"#, ClientMessage::GetState),
+            (r#"{"v":1,"type":"TAKEBACK"}"#, ClientMessage::Takeback),
+            (r#"{"v":1,"type":"ACCEPT_TAKEBACK"}"#, ClientMessage::AcceptTakeback),
+            (r#"{"v":1,"type":"DECLINE_TAKEBACK"}"#, ClientMessage::DeclineTakeback),
+            (r#"{"v":1,"type":"RESIGN"}"#, ClientMessage::Resign),
+            (r#"{"v":1,"type":"OFFER_DRAW"}"#, ClientMessage::OfferDraw),
+            (r#"{"v":1,"type":"ACCEPT_DRAW"}"#, ClientMessage::AcceptDraw),
+            (r#"{"v":1,"type":"DECLINE_DRAW"}"#, ClientMessage::DeclineDraw),
+            (r#"{"v":1,"type":"CHAT","text":"gg"}"#, ClientMessage::Chat { text: String::from("gg") }),
+        ];
+        for (frame, expected) in frames {
+            assert_eq!(decode(frame).unwrap(), expected, "{}", frame);
+        }
+    }
 
-fn ws_message_of(plain: &str) -> Message {
-    Message::Text(String::from(plain))
-}
+    #[test]
+    fn other_versions_are_rejected() {
+        assert!(matches!(decode(r#"{"v":2,"type":"REMATCH"}"#), Err(DecodeError::UnsupportedVersion(2))));
+        assert!(matches!(decode(r#"{"v":0,"type":"REMATCH"}"#), Err(DecodeError::UnsupportedVersion(0))));
+    }
 
-fn sent_fail_notify(_: TrySendError<Message>) {
-    println!("Could not send message.")
+    #[test]
+    fn malformed_frames_are_json_errors() {
+        assert!(matches!(decode(r#"{"v":1,"text":"4"}"#), Err(DecodeError::Json(_))));
+        assert!(matches!(decode(r#"{"v":1,"type":"FLIP_TABLE"}"#), Err(DecodeError::Json(_))));
+        assert!(matches!(decode(r#"{"v":1,"type":"CLIENT_CLICK","text":4}"#), Err(DecodeError::Json(_))));
+        assert!(matches!(decode(r#"{"type":"REMATCH"}"#), Err(DecodeError::Json(_))));
+        assert!(matches!(decode("4"), Err(DecodeError::Json(_))));
+        assert!(matches!(decode("not json at all {"), Err(DecodeError::Json(_))));
+    }
+
+    #[test]
+    fn binary_frames_are_rejected() {
+        let frame = Message::Binary(br#"{"v":1,"type":"REMATCH"}"#.to_vec());
+        assert!(matches!(ClientMessage::decode(&frame), Err(DecodeError::NotText)));
+    }
+
+    #[test]
+    fn server_messages_carry_the_envelope() {
+        let encoded = |message: ServerMessage| match message.encode() {
+            Message::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(
+            encoded(ServerMessage::Show(String::from("4"))),
+            serde_json::json!({"v": PROTOCOL_VERSION, "type": "SHOW", "text": "4"})
+        );
+        assert_eq!(
+            encoded(ServerMessage::Result(GameOver { reason: GameResult::Resignation, message: String::from("You resigned!") })),
+            serde_json::json!({"v": 1, "type": "RESULT", "text": {"reason": "RESIGNATION", "message": "You resigned!"}})
+        );
+        assert_eq!(
+            encoded(ServerMessage::Lines(vec![vec![String::from("0"), String::from("4"), String::from("8")]])),
+            serde_json::json!({"v": 1, "type": "LINES", "text": [["0", "4", "8"]]})
+        );
+    }
 }
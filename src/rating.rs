@@ -0,0 +1,62 @@
+/// Rating of new accounts, guests and the computer.
+pub const DEFAULT_RATING: f64 = 1500.0;
+/// Ratings move faster until this many rated games were played.
+const PROVISIONAL_GAMES: u32 = 30;
+const PROVISIONAL_K_FACTOR: f64 = 40.0;
+const K_FACTOR: f64 = 20.0;
+
+/// Chance of scoring against the opponent according to the Elo model.
+pub fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Rating after a game scored 1 for a win, 0.5 for a tie and 0 for a loss.
+pub fn updated_rating(rating: f64, opponent: f64, score: f64, rated_games: u32) -> f64 {
+    let k_factor = if rated_games < PROVISIONAL_GAMES { PROVISIONAL_K_FACTOR } else { K_FACTOR };
+    rating + k_factor * (score - expected_score(rating, opponent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLED: u32 = PROVISIONAL_GAMES;
+
+    fn changes(rating: f64, opponent: f64, score: f64) -> (f64, f64) {
+        (
+            updated_rating(rating, opponent, score, SETTLED) - rating,
+            updated_rating(opponent, rating, 1.0 - score, SETTLED) - opponent,
+        )
+    }
+
+    #[test]
+    fn points_won_are_points_lost() {
+        for (rating, opponent) in [(1500.0, 1500.0), (1600.0, 1400.0), (1200.0, 2100.0)] {
+            for score in [0.0, 0.5, 1.0] {
+                let (gained, lost) = changes(rating, opponent, score);
+                assert!((gained + lost).abs() < 1e-9, "{} vs {} scoring {}", rating, opponent, score);
+            }
+        }
+    }
+
+    #[test]
+    fn tie_between_equals_changes_nothing() {
+        assert_eq!(changes(1500.0, 1500.0, 0.5), (0.0, 0.0));
+        assert_eq!(updated_rating(1500.0, 1500.0, 0.5, 0), 1500.0);
+    }
+
+    #[test]
+    fn upsets_are_worth_more() {
+        let (underdog, _) = changes(1400.0, 1600.0, 1.0);
+        let (favourite, _) = changes(1600.0, 1400.0, 1.0);
+        assert!(underdog > favourite);
+        assert!((expected_score(1400.0, 1600.0) + expected_score(1600.0, 1400.0) - 1.0).abs() < 1e-12);
+        assert_eq!(changes(1500.0, 1500.0, 1.0).0, K_FACTOR / 2.0);
+    }
+
+    #[test]
+    fn provisional_ratings_move_faster() {
+        assert_eq!(updated_rating(1500.0, 1500.0, 1.0, 0) - 1500.0, PROVISIONAL_K_FACTOR / 2.0);
+        assert_eq!(updated_rating(1500.0, 1500.0, 1.0, SETTLED) - 1500.0, K_FACTOR / 2.0);
+    }
+}
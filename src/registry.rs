@@ -0,0 +1,139 @@
+use crate::actor::{spawn_session, SessionHandle};
+use crate::clock::TimeControl;
+use crate::database::{GameDatabase, GameRecord};
+use crate::message::GameMessageFactory;
+use crate::session::GameSession;
+use crate::variant::Variant;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Sent by sessions to the reaper.
+pub enum SessionEvent {
+    /// A game ended, stored right away so that a crashing session loses none.
+    Finished(GameRecord),
+    Closed(u64),
+}
+
+/// What a public lobby plays: players are only paired within the same kind.
+pub type LobbyKind = (Variant, Option<TimeControl>);
+
+/// Live sessions indexed by id, with the public lobbies queued per kind of
+/// game and private rooms indexed by code, so that joining never scans
+/// unrelated games.
+pub struct SessionRegistry {
+    sessions: HashMap<u64, SessionHandle>,
+    /// Public lobbies, oldest first.
+    waiting: HashMap<LobbyKind, VecDeque<u64>>,
+    rooms: HashMap<String, u64>,
+    events: UnboundedSender<SessionEvent>,
+}
+
+impl SessionRegistry {
+    /// The registry and the events of its sessions, to be handed to spawn_reaper.
+    pub fn new() -> (SessionRegistry, UnboundedReceiver<SessionEvent>) {
+        let (events, events_receiver) = unbounded();
+        let registry = SessionRegistry {
+            sessions: HashMap::new(),
+            waiting: HashMap::new(),
+            rooms: HashMap::new(),
+            events,
+        };
+        (registry, events_receiver)
+    }
+
+    /// Starts the session task and registers it, queueing public lobbies.
+    pub fn insert(&mut self, session: GameSession, game_message_factory: Arc<GameMessageFactory>) -> SessionHandle {
+        let handle = spawn_session(session, game_message_factory, self.events.clone());
+        let status = handle.status();
+        if let Some(code) = status.room {
+            self.rooms.insert(code, handle.id);
+        } else if status.waiting_rating().is_some() {
+            self.waiting.entry(status.kind).or_default().push_back(handle.id);
+        }
+        self.sessions.insert(handle.id, handle.clone());
+        handle
+    }
+
+    pub fn get(&self, id: u64) -> Option<&SessionHandle> {
+        self.sessions.get(&id)
+    }
+
+    pub fn room(&self, code: &str) -> Option<&SessionHandle> {
+        self.sessions.get(self.rooms.get(code)?)
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &SessionHandle> {
+        self.sessions.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Public lobbies of this kind still waiting, oldest first. Those joined
+    /// since the last call are dropped from the queue on the way.
+    pub fn waiting(&mut self, kind: &LobbyKind) -> Vec<SessionHandle> {
+        let Some(queue) = self.waiting.get_mut(kind) else { return Vec::new() };
+        let sessions = &self.sessions;
+        queue.retain(|id| sessions.get(id).is_some_and(|handle| handle.status().waiting_rating().is_some()));
+        let lobbies = queue.iter().filter_map(|id| sessions.get(id).cloned()).collect();
+        if queue.is_empty() {
+            self.waiting.remove(kind);
+        }
+        lobbies
+    }
+
+    pub fn lobby_kinds(&self) -> Vec<LobbyKind> {
+        self.waiting.keys().copied().collect()
+    }
+
+    fn remove(&mut self, id: u64) {
+        let Some(handle) = self.sessions.remove(&id) else { return };
+        let status = handle.status();
+        match status.room {
+            Some(code) => {
+                self.rooms.remove(&code);
+            }
+            None => {
+                if let Some(queue) = self.waiting.get_mut(&status.kind) {
+                    queue.retain(|&waiting| waiting != id);
+                    if queue.is_empty() {
+                        self.waiting.remove(&status.kind);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Stores finished games as they end and drops sessions from the registry
+/// as soon as they close.
+pub fn spawn_reaper(
+    registry: Arc<Mutex<SessionRegistry>>,
+    mut events: UnboundedReceiver<SessionEvent>,
+    database: Arc<GameDatabase>,
+) {
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                SessionEvent::Finished(record) => {
+                    let database = Arc::clone(&database);
+                    tokio::task::spawn_blocking(move || match database.save_game(&record) {
+                        Ok(_) => println!("Stored a finished game of session {}", record.session_id),
+                        Err(e) => println!("Could not store game: {}", e),
+                    });
+                }
+                SessionEvent::Closed(id) => {
+                    let left = {
+                        let mut registry = registry.lock().unwrap();
+                        registry.remove(id);
+                        registry.len()
+                    };
+                    println!("Session {} closed, {} left", id, left);
+                }
+            }
+        }
+    });
+}
@@ -21,6 +21,10 @@ pub struct StaticResource {
     pub o_cell: Vec<u8>,
     pub css: Vec<u8>,
     pub favicon: Vec<u8>,
+    pub replay_page: Vec<u8>,
+    pub replay_javascript: Vec<u8>,
+    pub leaderboard_page: Vec<u8>,
+    pub leaderboard_javascript: Vec<u8>,
 }
 
 impl StaticResource {
@@ -34,6 +38,10 @@ impl StaticResource {
                 read_resource("src/static/images/o-cell.jpg"),
                 read_resource("src/static/grid.css"),
                 read_resource("src/static/images/favicon.png"),
+                read_resource("src/static/replay.html"),
+                read_resource("src/static/replay.js"),
+                read_resource("src/static/leaderboard.html"),
+                read_resource("src/static/leaderboard.js"),
             );
             StaticResource {
                 homepage: resources.0,
@@ -43,6 +51,10 @@ impl StaticResource {
                 o_cell: resources.4,
                 css: resources.5,
                 favicon: resources.6,
+                replay_page: resources.7,
+                replay_javascript: resources.8,
+                leaderboard_page: resources.9,
+                leaderboard_javascript: resources.10,
             }
         }).await
     }
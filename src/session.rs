@@ -1,95 +1,436 @@
-use crate::board::{CellOwner, GameBoard};
-use crate::message::{message_send, multi_message_send, GameMessageFactory, MessageType};
+use crate::board::notation::{Notation, UNFINISHED};
+use crate::board::CellOwner;
+use crate::chat::{clean_message, ChatError, ChatLimiter};
+use crate::clock::{Clock, TimeControl};
+use crate::database::{unix_time, unix_time_ms, GameRecord, GameResult, MoveRecord};
+use crate::message::{message_send, multi_message_send, ClientMessage, GameMessageFactory, GameState, ServerMessage};
+use crate::rating::DEFAULT_RATING;
+use crate::registry::{LobbyKind, SessionEvent};
+use serde::{Deserialize, Serialize};
+use crate::variant::{Variant, VariantBoard};
 use futures_channel::mpsc::UnboundedSender;
+use rand::distributions::{Alphanumeric, DistString};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio_tungstenite::tungstenite::Message;
+use std::time::{Duration, Instant};
 
-#[derive(PartialEq)]
+const RESUME_TOKEN_LENGTH: usize = 24;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize, Serialize)]
 pub enum GameSessionPhase {
     LOBBY,
     PLAYING,
+    /// Game over, both players may still agree on a rematch.
+    FINISHED,
     CLOSED,
 }
 
+/// Why a move from a player was rejected.
+pub enum MoveError {
+    GameNotInProgress,
+    NotYourTurn,
+    IllegalMove(String),
+    NothingToTakeBack,
+    NoTakebackRequested,
+    NoDrawOffered,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoveError::GameNotInProgress => write!(f, "the game is not in progress"),
+            MoveError::NotYourTurn => write!(f, "it is not your turn"),
+            MoveError::IllegalMove(move_text) => write!(f, "illegal move {:?}", move_text),
+            MoveError::NothingToTakeBack => write!(f, "you have no move to take back"),
+            MoveError::NoTakebackRequested => write!(f, "your opponent did not ask for a takeback"),
+            MoveError::NoDrawOffered => write!(f, "your opponent did not offer a draw"),
+        }
+    }
+}
+
+/// Who sits on a seat.
+#[derive(Clone)]
+pub struct Player {
+    pub name: String,
+    /// None for guests and the computer.
+    pub account: Option<i64>,
+    pub rating: f64,
+}
+
+impl Player {
+    pub fn unrated(name: String) -> Player {
+        Player { name, account: None, rating: DEFAULT_RATING }
+    }
+}
+
 pub struct GameSession {
-    pub board: GameBoard,
+    pub id: u64,
+    pub board: VariantBoard,
     pub phase: GameSessionPhase,
     pub turn: CellOwner,
-    sender_a: Arc<UnboundedSender<Message>>,
-    pub sender_b: Option<Arc<UnboundedSender<Message>>>,
+    /// The seat playing X this game, swapped on every rematch.
+    first_player: CellOwner,
+    rematch_request: Option<CellOwner>,
+    /// Seat asking to undo its last move, dropped as soon as anyone moves.
+    takeback_request: Option<CellOwner>,
+    /// Seat offering a tie, dropped as soon as anyone moves.
+    draw_offer: Option<CellOwner>,
+    // per seat, PlayerA first
+    chat_limiters: [ChatLimiter; 2],
+    // games won per seat across rematches, PlayerA first
+    wins: [u32; 2],
+    ties: u32,
+    clock: Option<Clock>,
+    // per seat, PlayerA first, as written to the database
+    players: [Player; 2],
+    /// Accepted moves of the current game, in order.
+    moves: Vec<MoveRecord>,
+    started_at: u64,
+    /// The game just finished, already sent to be stored.
+    last_record: Option<GameRecord>,
+    sender_a: Arc<UnboundedSender<ServerMessage>>,
+    pub sender_b: Option<Arc<UnboundedSender<ServerMessage>>>,
+    /// Invite code of a private room, None for public matchmaking.
+    pub room: Option<String>,
+    // per seat, PlayerA first: a seat without resume token is not a human player
+    resume_tokens: [Option<String>; 2],
+    connected: [bool; 2],
+    /// Bumped on every reconnection, so that the grace timer of an older
+    /// connection does not close a resumed game.
+    connection_ids: [u32; 2],
+    spectators: Vec<Arc<UnboundedSender<ServerMessage>>>,
+    waiting_since: Instant,
+    /// Told every finished game and the session id once it closes.
+    event_sink: Option<UnboundedSender<SessionEvent>>,
+}
+
+/// What can be known about a session without asking its task.
+#[derive(Clone)]
+pub struct SessionStatus {
+    pub phase: GameSessionPhase,
+    pub room: Option<String>,
+    pub kind: LobbyKind,
+    /// Of the player who opened the session.
+    rating: f64,
+    waiting_since: Instant,
+}
+
+impl SessionStatus {
+    /// Rating of the player waiting for a random opponent and for how long,
+    /// None unless this is a public lobby.
+    pub fn waiting_rating(&self) -> Option<(f64, Duration)> {
+        (self.phase == GameSessionPhase::LOBBY && self.room.is_none())
+            .then(|| (self.rating, self.waiting_since.elapsed()))
+    }
 }
 
 impl GameSession {
-    pub fn new(sender_a: Arc<UnboundedSender<Message>>) -> GameSession {
+    pub fn new(sender_a: Arc<UnboundedSender<ServerMessage>>, variant: Variant, time_control: Option<TimeControl>) -> GameSession {
         GameSession {
-            board: GameBoard::new(),
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            board: VariantBoard::new(variant),
             phase: GameSessionPhase::LOBBY,
             turn: CellOwner::PlayerA,
+            first_player: CellOwner::PlayerA,
+            rematch_request: None,
+            takeback_request: None,
+            draw_offer: None,
+            chat_limiters: Default::default(),
+            wins: [0, 0],
+            ties: 0,
+            clock: time_control.map(Clock::new),
+            players: [Player::unrated(String::from("guest")), Player::unrated(String::from("guest"))],
+            moves: Vec::new(),
+            started_at: unix_time(),
+            last_record: None,
             sender_a,
             sender_b: None,
+            room: None,
+            resume_tokens: [None, None],
+            connected: [true, true],
+            connection_ids: [0, 0],
+            spectators: Vec::new(),
+            waiting_since: Instant::now(),
+            event_sink: None,
+        }
+    }
+
+    pub fn time_control(&self) -> Option<TimeControl> {
+        self.clock.as_ref().map(Clock::time_control)
+    }
+
+    pub fn report_to(&mut self, sink: UnboundedSender<SessionEvent>) {
+        self.event_sink = Some(sink);
+    }
+
+    pub fn status(&self) -> SessionStatus {
+        SessionStatus {
+            phase: self.phase,
+            room: self.room.clone(),
+            kind: (self.board.variant(), self.time_control()),
+            rating: self.players[0].rating,
+            waiting_since: self.waiting_since,
         }
     }
 
+    pub fn set_player(&mut self, player: CellOwner, seated: Player) {
+        self.players[seat(player)] = seated;
+    }
+
+    /// Seats the opponent of the waiting player and starts the game.
+    pub fn join(&mut self, sender: Arc<UnboundedSender<ServerMessage>>, seated: Player, game_message_factory: &GameMessageFactory) {
+        message_send(&sender, &ServerMessage::Board(self.board.variant().to_string()));
+        let token = self.issue_resume_token(CellOwner::PlayerB);
+        message_send(&sender, &ServerMessage::Token(token));
+        self.players[1] = seated;
+        self.sender_b = Some(sender);
+        self.start_game(game_message_factory);
+    }
+
+    /// The player who opened the session, to be moved to another lobby.
+    pub fn waiting_player(&self) -> (Arc<UnboundedSender<ServerMessage>>, Player) {
+        (Arc::clone(&self.sender_a), self.players[0].clone())
+    }
+
+    /// Closes this lobby once its waiting player sits in another session.
+    pub fn hand_over(&mut self) {
+        self.close();
+    }
+
+    /// The current game, or the one just finished.
+    pub fn notation(&self) -> Notation {
+        let record = if self.phase == GameSessionPhase::PLAYING { None } else { self.last_record.as_ref() };
+        Notation {
+            variant: self.board.variant(),
+            player_x: self.players[seat(self.first_player)].name.clone(),
+            player_o: self.players[seat(self.first_player.opponent())].name.clone(),
+            date: Notation::date_of(self.started_at),
+            result: String::from(record.map_or(UNFINISHED, |record| Notation::result_of(record.winner))),
+            termination: record.and_then(|record| Notation::termination_of(record.result.name())),
+            moves: self.moves.iter().map(|played| played.cell.clone()).collect(),
+        }
+    }
+
+    pub fn add_spectator(&mut self, sender: Arc<UnboundedSender<ServerMessage>>, game_message_factory: &GameMessageFactory) {
+        println!("Spectator joined session {}", self.id);
+        let status = if self.phase == GameSessionPhase::LOBBY {
+            game_message_factory.get_default(GameMessageFactory::WAITING_MESSAGE)
+        } else {
+            self.spectator_turn_message(game_message_factory)
+        };
+        self.send_snapshot(&sender, None, status);
+        self.spectators.push(sender);
+    }
+
+    pub fn remove_spectator(&mut self, sender: &Arc<UnboundedSender<ServerMessage>>) {
+        self.spectators.retain(|spectator| !Arc::ptr_eq(spectator, sender));
+    }
+
+    /// Hands out the token the player can present to take the seat back after
+    /// losing the connection.
+    /// The token starts with the session id, see session_of_token.
+    pub fn issue_resume_token(&mut self, player: CellOwner) -> String {
+        let token = format!("{}-{}", self.id, Alphanumeric.sample_string(&mut rand::thread_rng(), RESUME_TOKEN_LENGTH));
+        self.resume_tokens[seat(player)] = Some(token.clone());
+        token
+    }
+
+    /// Id of the session a resume token was issued by.
+    pub fn session_of_token(token: &str) -> Option<u64> {
+        token.split_once('-')?.0.parse().ok()
+    }
+
+    pub fn seat_of_token(&self, token: &str) -> Option<CellOwner> {
+        [CellOwner::PlayerA, CellOwner::PlayerB].into_iter()
+            .find(|&player| self.resume_tokens[seat(player)].as_deref() == Some(token))
+    }
+
+    pub fn connection_id(&self, player: CellOwner) -> u32 {
+        self.connection_ids[seat(player)]
+    }
+
+    /// Called when a player socket drops: returns true if the seat is kept for
+    /// a reconnection, otherwise the session is closed.
+    pub fn disconnect(&mut self, player: CellOwner, connection_id: u32, game_message_factory: &GameMessageFactory) -> bool {
+        if self.connection_ids[seat(player)] != connection_id {
+            println!("Stale connection dropped, the player already resumed");
+            return false;
+        }
+        if self.phase != GameSessionPhase::PLAYING || self.resume_tokens[seat(player)].is_none() {
+            self.close_session(player, game_message_factory);
+            return false;
+        }
+        println!("Keeping seat for a reconnection");
+        self.connected[seat(player)] = false;
+        message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::OPPONENT_AWAY_MESSAGE));
+        self.spectator_send(&[game_message_factory.get_default(GameMessageFactory::PLAYER_AWAY_MESSAGE)]);
+        true
+    }
+
+    /// Closes the session if the player did not come back since the given connection dropped.
+    pub fn expire_seat(&mut self, player: CellOwner, connection_id: u32, game_message_factory: &GameMessageFactory) {
+        if !self.connected[seat(player)] && self.connection_ids[seat(player)] == connection_id {
+            println!("Reconnection grace period expired");
+            self.close_session(player, game_message_factory);
+        }
+    }
+
+    pub fn resume(&mut self, player: CellOwner, sender: Arc<UnboundedSender<ServerMessage>>, game_message_factory: &GameMessageFactory) {
+        println!("Player resumed the game");
+        if player == CellOwner::PlayerA {
+            self.sender_a = sender;
+        } else {
+            self.sender_b = Some(sender);
+        }
+        self.connected[seat(player)] = true;
+        self.connection_ids[seat(player)] += 1;
+        if self.sender_b.is_none() {
+            // still in the lobby: there is no one else to tell
+            self.send_snapshot(&self.sender_a, Some(player), game_message_factory.get_default(GameMessageFactory::WAITING_MESSAGE));
+            return;
+        }
+        if self.phase != GameSessionPhase::PLAYING {
+            // the game ended while the player was away: repeat the result, nobody is to move
+            self.send_snapshot(&self.player_sink(player), Some(player), self.result_message(player, game_message_factory));
+            if self.rematch_request == Some(player.opponent()) {
+                message_send(&self.player_sink(player), game_message_factory.get_default(GameMessageFactory::REMATCH_OFFER_MESSAGE));
+            }
+            return;
+        }
+        let turn_message = if self.turn == player {
+            GameMessageFactory::YOUR_TURN_MESSAGE
+        } else {
+            GameMessageFactory::OPPONENT_TURN_MESSAGE
+        };
+        self.send_snapshot(&self.player_sink(player), Some(player), game_message_factory.get_default(turn_message));
+        let opponent_turn = if self.turn == player {
+            GameMessageFactory::OPPONENT_TURN_MESSAGE
+        } else {
+            GameMessageFactory::YOUR_TURN_MESSAGE
+        };
+        message_send(&self.opponent_sink(player), game_message_factory.get_default(opponent_turn));
+        self.spectator_send(&[self.spectator_turn_message(game_message_factory)]);
+    }
+
     pub fn start_game(&mut self, game_message_factory: &GameMessageFactory) {
         println!("Starting game");
         self.phase = GameSessionPhase::PLAYING;
-        message_send(&self.sender_a, game_message_factory.get_default(GameMessageFactory::YOUR_TURN_MESSAGE));
+        self.moves.clear();
+        self.takeback_request = None;
+        self.draw_offer = None;
+        self.started_at = unix_time();
+        if let Some(clock) = &mut self.clock {
+            clock.start(self.turn);
+        }
+        let (message_a, message_b) = if self.turn == CellOwner::PlayerA {
+            (GameMessageFactory::YOUR_TURN_MESSAGE, GameMessageFactory::OPPONENT_TURN_MESSAGE)
+        } else {
+            (GameMessageFactory::OPPONENT_TURN_MESSAGE, GameMessageFactory::YOUR_TURN_MESSAGE)
+        };
+        message_send(&self.sender_a, game_message_factory.get_default(message_a));
         match &self.sender_b {
             Some(sender) => {
-                message_send(sender, game_message_factory.get_default(GameMessageFactory::OPPONENT_TURN_MESSAGE));
+                message_send(sender, game_message_factory.get_default(message_b));
             }
             None => { println!("Error starting game B") }
         }
+        self.spectator_send(&[self.spectator_turn_message(game_message_factory)]);
+        self.send_playable_boards();
+        self.send_state(&self.player_sink(CellOwner::PlayerA), Some(CellOwner::PlayerA));
+        self.send_state(&self.player_sink(CellOwner::PlayerB), Some(CellOwner::PlayerB));
+        for spectator in &self.spectators {
+            self.send_state(spectator, None);
+        }
     }
 
     pub fn process_player_input(
         &mut self,
         player: CellOwner,
-        (input_text, input_type): (String, String),
+        input: ClientMessage,
         game_message_factory: &GameMessageFactory,
     ) {
-        if self.update_board(player, &input_text, &input_type) {
-            println!("Board updated!");
-            let figure_message = if player == CellOwner::PlayerA {
-                game_message_factory.get_default(GameMessageFactory::X_FIGURE_MESSAGE)
-            } else {
-                game_message_factory.get_default(GameMessageFactory::O_FIGURE_MESSAGE)
-            };
-            let show_message = &GameMessageFactory::build_plain_message(&input_text, MessageType::SHOW);
-            let winner = self.board.check_winner();
-            if winner == CellOwner::None {
-                self.turn = self.turn.opponent();
-                multi_message_send(
-                    &self.opponent_sink(player),
-                    &[figure_message, show_message,
-                        game_message_factory.get_default(GameMessageFactory::YOUR_TURN_MESSAGE)],
-                );
-                multi_message_send(
-                    &self.opponent_sink(player.opponent()),
-                    &[figure_message, show_message,
-                        game_message_factory.get_default(GameMessageFactory::OPPONENT_TURN_MESSAGE)],
-                );
-            } else if winner == player {
-                self.phase = GameSessionPhase::CLOSED;
-                multi_message_send(
-                    &self.opponent_sink(player),
-                    &[figure_message, show_message,
-                        game_message_factory.get_default(GameMessageFactory::LOST_MESSAGE)],
-                );
-                multi_message_send(
-                    &self.opponent_sink(player.opponent()),
-                    &[figure_message, show_message,
-                        game_message_factory.get_default(GameMessageFactory::WIN_MESSAGE)],
-                );
-            } else if winner == CellOwner::Tie {
-                self.phase = GameSessionPhase::CLOSED;
-                let tie_messages = &[figure_message, show_message,
-                    game_message_factory.get_default(GameMessageFactory::TIE_MESSAGE)];
-                multi_message_send(
-                    &*self.opponent_sink(player), tie_messages);
-                multi_message_send(
-                    &*self.opponent_sink(player.opponent()), tie_messages);
+        if self.phase == GameSessionPhase::PLAYING && self.clock.as_ref().is_some_and(Clock::is_flagged) {
+            // the input came in after the time was up, before the clock ticked:
+            // neither a move nor a draw or a takeback may save the game
+            return self.time_out(game_message_factory);
+        }
+        let move_text = match input {
+            ClientMessage::Rematch => return self.request_rematch(player, game_message_factory),
+            ClientMessage::GetState => return self.send_state(&self.player_sink(player), Some(player)),
+            ClientMessage::Takeback => return self.request_takeback(player, game_message_factory),
+            ClientMessage::AcceptTakeback => return self.answer_takeback(player, true, game_message_factory),
+            ClientMessage::DeclineTakeback => return self.answer_takeback(player, false, game_message_factory),
+            ClientMessage::Resign => return self.resign(player, game_message_factory),
+            ClientMessage::OfferDraw => return self.offer_draw(player, game_message_factory),
+            ClientMessage::AcceptDraw => return self.answer_draw(player, true, game_message_factory),
+            ClientMessage::DeclineDraw => return self.answer_draw(player, false, game_message_factory),
+            ClientMessage::Chat { text } => return self.chat(player, &text),
+            ClientMessage::ClientClick { text } => text,
+        };
+        let move_text = match self.update_board(player, &move_text) {
+            Ok(played) => played,
+            Err(error) => {
+                println!("Rejected move: {}", error);
+                return message_send(&self.player_sink(player), &ServerMessage::Error(error.to_string()));
+            }
+        };
+        println!("Board updated!");
+        let figure_message = self.figure_message(player, game_message_factory);
+        let show_message = &ServerMessage::Show(move_text);
+        let outcome = self.board.check_outcome();
+        let winner = outcome.winner;
+        if winner == CellOwner::None {
+            self.turn = self.turn.opponent();
+            if let Some(clock) = &mut self.clock {
+                clock.switch();
             }
+            multi_message_send(
+                &self.opponent_sink(player),
+                &[figure_message, show_message,
+                    game_message_factory.get_default(GameMessageFactory::YOUR_TURN_MESSAGE)],
+            );
+            multi_message_send(
+                &self.opponent_sink(player.opponent()),
+                &[figure_message, show_message,
+                    game_message_factory.get_default(GameMessageFactory::OPPONENT_TURN_MESSAGE)],
+            );
+            self.spectator_send(&[figure_message, show_message, self.spectator_turn_message(game_message_factory)]);
+            self.send_playable_boards();
+        } else if winner == player {
+            self.phase = GameSessionPhase::FINISHED;
+            self.wins[seat(player)] += 1;
+            self.record_game(player, GameResult::Line);
+            let lines_message = &ServerMessage::Lines(outcome.lines);
+            multi_message_send(
+                &self.opponent_sink(player),
+                &[figure_message, show_message, lines_message,
+                    game_message_factory.get_default(GameMessageFactory::LOST_MESSAGE)],
+            );
+            multi_message_send(
+                &self.opponent_sink(player.opponent()),
+                &[figure_message, show_message, lines_message,
+                    game_message_factory.get_default(GameMessageFactory::WIN_MESSAGE)],
+            );
+            self.spectator_send(&[figure_message, show_message, lines_message,
+                self.spectator_won_message(player, game_message_factory)]);
+            self.send_score();
+        } else if winner == CellOwner::Tie {
+            self.phase = GameSessionPhase::FINISHED;
+            self.ties += 1;
+            self.record_game(CellOwner::Tie, GameResult::Tie);
+            let tie_messages = &[figure_message, show_message,
+                game_message_factory.get_default(GameMessageFactory::TIE_MESSAGE)];
+            multi_message_send(
+                &self.opponent_sink(player), tie_messages);
+            multi_message_send(
+                &self.opponent_sink(player.opponent()), tie_messages);
+            self.spectator_send(&[figure_message, show_message,
+                game_message_factory.get_default(GameMessageFactory::SPECTATOR_TIE_MESSAGE)]);
+            self.send_score();
         }
     }
 
@@ -98,21 +439,394 @@ impl GameSession {
             println!("Nothing to do, session already closed");
         } else {
             println!("Player let game before end");
-            if self.phase == GameSessionPhase::PLAYING { // if playing there must be an opponent, otherwise panic
+            if self.phase == GameSessionPhase::PLAYING {
+                self.record_game(player.opponent(), GameResult::Withdrawal);
+                message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::OPPONENT_WITHDREW_MESSAGE));
+                self.spectator_send(&[game_message_factory.get_default(GameMessageFactory::SPECTATOR_WITHDRAWAL_MESSAGE)]);
+            }
+            // if playing or finished there must be an opponent, otherwise panic
+            if self.phase == GameSessionPhase::PLAYING || self.phase == GameSessionPhase::FINISHED {
                 message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::WITHDRAWAL_MESSAGE));
             }
-            self.phase = GameSessionPhase::CLOSED;
+            self.spectator_send(&[game_message_factory.get_default(GameMessageFactory::PLAYER_LEFT_MESSAGE)]);
+            self.close();
+        }
+    }
+
+    fn close(&mut self) {
+        self.phase = GameSessionPhase::CLOSED;
+        if let Some(sink) = &self.event_sink {
+            sink.unbounded_send(SessionEvent::Closed(self.id))
+                .unwrap_or_else(|e| println!("Could not report closed session: {}", e));
+        }
+    }
+
+    /// Broadcasts the remaining time, the player to move loses once out of time.
+    pub fn tick_clock(&mut self, game_message_factory: &GameMessageFactory) {
+        let Some(clock) = &self.clock else { return };
+        if self.phase != GameSessionPhase::PLAYING {
+            return;
+        }
+        if clock.is_flagged() {
+            self.time_out(game_message_factory);
+            return;
+        }
+        // seconds left, own clock first for players and X first for spectators
+        let clock_of = |player: CellOwner| ServerMessage::Clock(
+            format!("{},{}", clock.left(player).as_secs(), clock.left(player.opponent()).as_secs())
+        );
+        message_send(&self.player_sink(CellOwner::PlayerA), &clock_of(CellOwner::PlayerA));
+        message_send(&self.player_sink(CellOwner::PlayerB), &clock_of(CellOwner::PlayerB));
+        self.spectator_send(&[&clock_of(self.first_player)]);
+    }
+
+    fn time_out(&mut self, game_message_factory: &GameMessageFactory) {
+        println!("Player ran out of time");
+        let loser = self.turn;
+        self.phase = GameSessionPhase::FINISHED;
+        self.wins[seat(loser.opponent())] += 1;
+        self.record_game(loser.opponent(), GameResult::Timeout);
+        message_send(&self.player_sink(loser), game_message_factory.get_default(GameMessageFactory::TIME_OUT_LOST_MESSAGE));
+        message_send(&self.opponent_sink(loser), game_message_factory.get_default(GameMessageFactory::TIME_OUT_WIN_MESSAGE));
+        self.spectator_send(&[self.spectator_won_message(loser.opponent(), game_message_factory)]);
+        self.send_score();
+    }
+
+    /// Sends the game that just ended to be stored, winner is Tie for a tie.
+    fn record_game(&mut self, winner: CellOwner, result: GameResult) {
+        let record = GameRecord {
+            session_id: self.id,
+            variant: self.board.variant().to_string(),
+            player_x: self.players[seat(self.first_player)].name.clone(),
+            player_o: self.players[seat(self.first_player.opponent())].name.clone(),
+            account_x: self.players[seat(self.first_player)].account,
+            account_o: self.players[seat(self.first_player.opponent())].account,
+            winner: (winner != CellOwner::Tie).then(|| self.figure_letter(winner)),
+            result,
+            moves: self.moves.clone(),
+            started_at: self.started_at,
+            ended_at: unix_time(),
+        };
+        if let Some(sink) = &self.event_sink {
+            sink.unbounded_send(SessionEvent::Finished(record.clone()))
+                .unwrap_or_else(|e| println!("Could not report finished game: {}", e));
+        }
+        self.last_record = Some(record);
+    }
+
+    /// A rematch starts once both players of a finished game asked for it.
+    fn request_rematch(&mut self, player: CellOwner, game_message_factory: &GameMessageFactory) {
+        if self.phase != GameSessionPhase::FINISHED {
+            return;
+        }
+        match self.rematch_request {
+            Some(requester) if requester == player => println!("Rematch already requested"),
+            Some(_) => self.start_rematch(game_message_factory),
+            None => {
+                self.rematch_request = Some(player);
+                message_send(&self.player_sink(player), game_message_factory.get_default(GameMessageFactory::REMATCH_WAITING_MESSAGE));
+                message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::REMATCH_OFFER_MESSAGE));
+            }
+        }
+    }
+
+    /// Moves to undo so that the player is to move again right before their
+    /// last move: theirs and the reply of the opponent if any.
+    fn takeback_plies(&self, player: CellOwner) -> usize {
+        let letter = self.figure_letter(player);
+        match self.moves.last() {
+            Some(last) if last.player == letter => 1,
+            Some(_) if self.moves.len() >= 2 => 2,
+            _ => 0,
+        }
+    }
+
+    fn request_takeback(&mut self, player: CellOwner, game_message_factory: &GameMessageFactory) {
+        if self.phase != GameSessionPhase::PLAYING {
+            message_send(&self.player_sink(player), &ServerMessage::Error(MoveError::GameNotInProgress.to_string()));
+        } else if self.takeback_plies(player) == 0 {
+            message_send(&self.player_sink(player), &ServerMessage::Error(MoveError::NothingToTakeBack.to_string()));
+        } else if self.takeback_request != Some(player) {
+            self.takeback_request = Some(player);
+            message_send(&self.player_sink(player), game_message_factory.get_default(GameMessageFactory::TAKEBACK_WAITING_MESSAGE));
+            message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::TAKEBACK_OFFER_MESSAGE));
+        }
+    }
+
+    /// On accept the board goes back to the position before the last move of
+    /// the requester, who is then to move again.
+    fn answer_takeback(&mut self, player: CellOwner, accepted: bool, game_message_factory: &GameMessageFactory) {
+        let requester = player.opponent();
+        if self.phase != GameSessionPhase::PLAYING || self.takeback_request != Some(requester) {
+            message_send(&self.player_sink(player), &ServerMessage::Error(MoveError::NoTakebackRequested.to_string()));
+            return;
+        }
+        self.takeback_request = None;
+        if !accepted {
+            message_send(&self.player_sink(requester), game_message_factory.get_default(GameMessageFactory::TAKEBACK_DECLINED_MESSAGE));
+            return;
+        }
+        for _ in 0..self.takeback_plies(requester) {
+            match self.moves.last() {
+                Some(played) if self.board.undo_move(&played.cell) => {
+                    println!("Took back move {}", played.cell);
+                    self.moves.pop();
+                }
+                _ => break,
+            }
+        }
+        if self.turn != requester {
+            self.turn = requester;
+            if let Some(clock) = &mut self.clock {
+                clock.switch();
+            }
+        }
+        for seated in [requester, player] {
+            let turn_message = if seated == requester {
+                GameMessageFactory::YOUR_TURN_MESSAGE
+            } else {
+                GameMessageFactory::OPPONENT_TURN_MESSAGE
+            };
+            let sink = self.player_sink(seated);
+            self.send_state(&sink, Some(seated));
+            message_send(&sink, game_message_factory.get_default(turn_message));
+        }
+        for spectator in &self.spectators {
+            self.send_state(spectator, None);
+            message_send(spectator, self.spectator_turn_message(game_message_factory));
+        }
+    }
+
+    fn resign(&mut self, player: CellOwner, game_message_factory: &GameMessageFactory) {
+        if self.phase != GameSessionPhase::PLAYING {
+            message_send(&self.player_sink(player), &ServerMessage::Error(MoveError::GameNotInProgress.to_string()));
+            return;
+        }
+        println!("Player resigned");
+        self.phase = GameSessionPhase::FINISHED;
+        self.wins[seat(player.opponent())] += 1;
+        self.record_game(player.opponent(), GameResult::Resignation);
+        message_send(&self.player_sink(player), game_message_factory.get_default(GameMessageFactory::RESIGNED_MESSAGE));
+        message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::OPPONENT_RESIGNED_MESSAGE));
+        let spectator_message = if player == self.first_player {
+            GameMessageFactory::X_RESIGNED_MESSAGE
+        } else {
+            GameMessageFactory::O_RESIGNED_MESSAGE
+        };
+        self.spectator_send(&[game_message_factory.get_default(spectator_message)]);
+        self.send_score();
+    }
+
+    fn offer_draw(&mut self, player: CellOwner, game_message_factory: &GameMessageFactory) {
+        if self.phase != GameSessionPhase::PLAYING {
+            message_send(&self.player_sink(player), &ServerMessage::Error(MoveError::GameNotInProgress.to_string()));
+        } else if self.draw_offer == Some(player.opponent()) {
+            // both offered, that is an agreement
+            self.answer_draw(player, true, game_message_factory);
+        } else if self.draw_offer != Some(player) {
+            self.draw_offer = Some(player);
+            message_send(&self.player_sink(player), game_message_factory.get_default(GameMessageFactory::DRAW_WAITING_MESSAGE));
+            message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::DRAW_OFFER_MESSAGE));
+        }
+    }
+
+    fn answer_draw(&mut self, player: CellOwner, accepted: bool, game_message_factory: &GameMessageFactory) {
+        if self.phase != GameSessionPhase::PLAYING || self.draw_offer != Some(player.opponent()) {
+            message_send(&self.player_sink(player), &ServerMessage::Error(MoveError::NoDrawOffered.to_string()));
+            return;
+        }
+        self.draw_offer = None;
+        if !accepted {
+            message_send(&self.opponent_sink(player), game_message_factory.get_default(GameMessageFactory::DRAW_DECLINED_MESSAGE));
+            return;
+        }
+        println!("Draw agreed");
+        self.phase = GameSessionPhase::FINISHED;
+        self.ties += 1;
+        self.record_game(CellOwner::Tie, GameResult::AgreedDraw);
+        let draw_message = game_message_factory.get_default(GameMessageFactory::DRAW_AGREED_MESSAGE);
+        message_send(&self.player_sink(player), draw_message);
+        message_send(&self.opponent_sink(player), draw_message);
+        self.spectator_send(&[game_message_factory.get_default(GameMessageFactory::SPECTATOR_DRAW_AGREED_MESSAGE)]);
+        self.send_score();
+    }
+
+    /// Relays the message to everyone in the session, its author included.
+    fn chat(&mut self, player: CellOwner, text: &str) {
+        let relayed = if self.sender_b.is_none() {
+            Err(ChatError::NoOpponent)
+        } else {
+            clean_message(text).and_then(|text| if self.chat_limiters[seat(player)].allow() {
+                Ok(text)
+            } else {
+                Err(ChatError::TooFast)
+            })
+        };
+        match relayed {
+            Ok(text) => {
+                let chat_message = &ServerMessage::Chat(format!("{}: {}", self.figure_letter(player), text));
+                message_send(&self.player_sink(player), chat_message);
+                message_send(&self.opponent_sink(player), chat_message);
+                self.spectator_send(&[chat_message]);
+            }
+            Err(error) => message_send(&self.player_sink(player), &ServerMessage::Error(error.to_string())),
+        }
+    }
+
+    fn start_rematch(&mut self, game_message_factory: &GameMessageFactory) {
+        println!("Starting rematch");
+        self.board = VariantBoard::new(self.board.variant());
+        self.first_player = self.first_player.opponent();
+        self.turn = self.first_player;
+        self.rematch_request = None;
+        let board_message = ServerMessage::Board(self.board.variant().to_string());
+        message_send(&self.player_sink(CellOwner::PlayerA), &board_message);
+        message_send(&self.player_sink(CellOwner::PlayerB), &board_message);
+        self.spectator_send(&[&board_message]);
+        self.start_game(game_message_factory);
+    }
+
+    /// Sends the running score, from the point of view of each player.
+    fn send_score(&self) {
+        let score_of = |player: CellOwner| ServerMessage::Score(
+            format!("{},{},{}", self.wins[seat(player)], self.wins[seat(player.opponent())], self.ties)
+        );
+        let score_a = score_of(CellOwner::PlayerA);
+        message_send(&self.player_sink(CellOwner::PlayerA), &score_a);
+        message_send(&self.player_sink(CellOwner::PlayerB), &score_of(CellOwner::PlayerB));
+        self.spectator_send(&[&score_a]);
+    }
+
+    fn figure_message<'a>(&self, player: CellOwner, game_message_factory: &'a GameMessageFactory) -> &'a ServerMessage {
+        if player == self.first_player {
+            game_message_factory.get_default(GameMessageFactory::X_FIGURE_MESSAGE)
+        } else {
+            game_message_factory.get_default(GameMessageFactory::O_FIGURE_MESSAGE)
+        }
+    }
+
+    /// Answers the move as echoed to the clients and stored, in its canonical
+    /// text encoding.
+    fn update_board(&mut self, player: CellOwner, move_text: &str) -> Result<String, MoveError> {
+        if self.phase != GameSessionPhase::PLAYING {
+            Err(MoveError::GameNotInProgress)
+        } else if self.turn != player {
+            Err(MoveError::NotYourTurn)
+        } else {
+            let played = self.board.play(move_text, player)
+                .ok_or_else(|| MoveError::IllegalMove(String::from(move_text)))?;
+            self.moves.push(MoveRecord {
+                player: self.figure_letter(player),
+                cell: played.clone(),
+                played_at_ms: unix_time_ms(),
+            });
+            self.takeback_request = None;
+            self.draw_offer = None;
+            Ok(played)
+        }
+    }
+
+    fn send_playable_boards(&self) {
+        if let Some(active_message) = self.playable_boards_message() {
+            message_send(&self.sender_a, &active_message);
+            if let Some(sender) = &self.sender_b {
+                message_send(sender, &active_message);
+            }
+            self.spectator_send(&[&active_message]);
         }
     }
 
-    fn update_board(&mut self, player: CellOwner, message_text: &String, message_type: &String) -> bool {
-        self.phase == GameSessionPhase::PLAYING &&
-            self.turn == player &&
-            message_type == MessageType::CLIENT_CLICK &&
-            self.board.update_cell(message_text.parse().unwrap(), player)
+    fn spectator_send(&self, messages: &[&ServerMessage]) {
+        for spectator in &self.spectators {
+            multi_message_send(spectator, messages);
+        }
     }
 
-    fn opponent_sink(&self, player: CellOwner) -> Arc<UnboundedSender<Message>> {
+    fn spectator_turn_message<'a>(&self, game_message_factory: &'a GameMessageFactory) -> &'a ServerMessage {
+        if self.turn == self.first_player {
+            game_message_factory.get_default(GameMessageFactory::X_TURN_MESSAGE)
+        } else {
+            game_message_factory.get_default(GameMessageFactory::O_TURN_MESSAGE)
+        }
+    }
+
+    fn spectator_won_message<'a>(&self, winner: CellOwner, game_message_factory: &'a GameMessageFactory) -> &'a ServerMessage {
+        if winner == self.first_player {
+            game_message_factory.get_default(GameMessageFactory::X_WON_MESSAGE)
+        } else {
+            game_message_factory.get_default(GameMessageFactory::O_WON_MESSAGE)
+        }
+    }
+
+    /// The RESULT the player was sent when the last game ended.
+    fn result_message<'a>(&self, player: CellOwner, game_message_factory: &'a GameMessageFactory) -> &'a ServerMessage {
+        let record = self.last_record.as_ref().expect("a finished game has a record");
+        let won = record.winner == Some(self.figure_letter(player));
+        let message = match record.result {
+            GameResult::Line if won => GameMessageFactory::WIN_MESSAGE,
+            GameResult::Line => GameMessageFactory::LOST_MESSAGE,
+            GameResult::Tie => GameMessageFactory::TIE_MESSAGE,
+            GameResult::Timeout if won => GameMessageFactory::TIME_OUT_WIN_MESSAGE,
+            GameResult::Timeout => GameMessageFactory::TIME_OUT_LOST_MESSAGE,
+            GameResult::Resignation if won => GameMessageFactory::OPPONENT_RESIGNED_MESSAGE,
+            GameResult::Resignation => GameMessageFactory::RESIGNED_MESSAGE,
+            GameResult::AgreedDraw => GameMessageFactory::DRAW_AGREED_MESSAGE,
+            GameResult::Withdrawal => GameMessageFactory::OPPONENT_WITHDREW_MESSAGE,
+        };
+        game_message_factory.get_default(message)
+    }
+
+    fn playable_boards_message(&self) -> Option<ServerMessage> {
+        self.board.playable_boards().map(|boards| {
+            let boards: Vec<String> = boards.iter().map(|board| board.to_string()).collect();
+            ServerMessage::Active(boards.join(","))
+        })
+    }
+
+    /// The game as seen by the player, or by a spectator when None.
+    pub fn send_state(&self, sink: &UnboundedSender<ServerMessage>, viewer: Option<CellOwner>) {
+        let cells: Vec<String> = self.board.cells().into_iter()
+            .map(|owner| String::from(self.figure_name(owner)))
+            .collect();
+        message_send(sink, &ServerMessage::State(GameState {
+            variant: self.board.variant().to_string(),
+            move_count: cells.iter().filter(|&cell| cell != "empty-cell").count(),
+            cells,
+            turn: String::from(self.figure_name(self.turn)),
+            figure: viewer.map(|player| String::from(self.figure_name(player))),
+            phase: self.phase,
+            active_boards: self.board.playable_boards(),
+            winning_lines: self.board.check_outcome().lines,
+        }));
+    }
+
+    /// Redraws the whole board, for clients starting from an empty grid.
+    fn send_snapshot(&self, sink: &UnboundedSender<ServerMessage>, viewer: Option<CellOwner>, status_message: &ServerMessage) {
+        message_send(sink, &ServerMessage::Board(self.board.variant().to_string()));
+        self.send_state(sink, viewer);
+        message_send(sink, status_message);
+    }
+
+    fn figure_letter(&self, player: CellOwner) -> &'static str {
+        if player == self.first_player { "X" } else { "O" }
+    }
+
+    /// Image name of the figure a player draws with in the current game.
+    fn figure_name(&self, owner: CellOwner) -> &'static str {
+        if owner == CellOwner::None {
+            "empty-cell"
+        } else if owner == self.first_player {
+            "x-cell"
+        } else {
+            "o-cell"
+        }
+    }
+
+    fn player_sink(&self, player: CellOwner) -> Arc<UnboundedSender<ServerMessage>> {
+        self.opponent_sink(player.opponent())
+    }
+
+    fn opponent_sink(&self, player: CellOwner) -> Arc<UnboundedSender<ServerMessage>> {
         if player == CellOwner::PlayerA {
             self.sender_b.as_ref().unwrap().clone()
         } else {
@@ -120,3 +834,179 @@ impl GameSession {
         }
     }
 }
+
+pub fn seat(player: CellOwner) -> usize {
+    if player == CellOwner::PlayerA { 0 } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::BoardSize;
+    use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+
+    /// A classic game just started, X to move, with what each seat receives.
+    fn game() -> (GameSession, UnboundedReceiver<ServerMessage>, UnboundedReceiver<ServerMessage>) {
+        let (sender_a, receiver_a) = unbounded();
+        let (sender_b, receiver_b) = unbounded();
+        let mut session = GameSession::new(Arc::new(sender_a), Variant::Standard(BoardSize::CLASSIC), None);
+        session.join(Arc::new(sender_b), Player::unrated(String::from("bob")), &GameMessageFactory::new());
+        (session, receiver_a, receiver_b)
+    }
+
+    fn received(receiver: &mut UnboundedReceiver<ServerMessage>) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(Some(message)) = receiver.try_next() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn click(session: &mut GameSession, player: CellOwner, text: &str) {
+        session.process_player_input(player, ClientMessage::ClientClick { text: String::from(text) }, &GameMessageFactory::new());
+    }
+
+    #[test]
+    fn move_is_answered_in_its_canonical_encoding() {
+        let (mut session, _receiver_a, mut receiver_b) = game();
+        received(&mut receiver_b);
+        click(&mut session, CellOwner::PlayerA, "+1");
+        assert!(received(&mut receiver_b).contains(&ServerMessage::Show(String::from("1"))));
+        assert_eq!(session.moves.iter().map(|played| played.cell.as_str()).collect::<Vec<_>>(), vec!["1"]);
+        assert!(matches!(session.update_board(CellOwner::PlayerB, "07"), Ok(played) if played == "7"));
+    }
+
+    #[test]
+    fn out_of_range_cells_are_illegal() {
+        let (mut session, ..) = game();
+        for text in ["9", "-1", "18446744073709551616", ""] {
+            assert!(matches!(session.update_board(CellOwner::PlayerA, text), Err(MoveError::IllegalMove(_))), "{:?}", text);
+        }
+        assert!(session.moves.is_empty());
+    }
+
+    #[test]
+    fn non_numeric_cells_are_illegal() {
+        let (mut session, ..) = game();
+        for text in ["b2", "4.0", "0-4", "four"] {
+            assert!(matches!(session.update_board(CellOwner::PlayerA, text), Err(MoveError::IllegalMove(_))), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn taken_cells_are_illegal() {
+        let (mut session, ..) = game();
+        assert!(session.update_board(CellOwner::PlayerA, "4").is_ok());
+        session.turn = CellOwner::PlayerB;
+        assert!(matches!(session.update_board(CellOwner::PlayerB, "4"), Err(MoveError::IllegalMove(_))));
+        assert_eq!(session.moves.len(), 1);
+    }
+
+    #[test]
+    fn moves_out_of_turn_are_answered_with_an_error() {
+        let (mut session, mut receiver_a, mut receiver_b) = game();
+        received(&mut receiver_a);
+        received(&mut receiver_b);
+        click(&mut session, CellOwner::PlayerB, "4");
+        assert_eq!(received(&mut receiver_b), vec![ServerMessage::Error(MoveError::NotYourTurn.to_string())]);
+        assert!(received(&mut receiver_a).is_empty());
+        assert!(session.board.cells().iter().all(|&owner| owner == CellOwner::None));
+        click(&mut session, CellOwner::PlayerA, "x");
+        assert_eq!(received(&mut receiver_a), vec![ServerMessage::Error(MoveError::IllegalMove(String::from("x")).to_string())]);
+        assert!(session.turn == CellOwner::PlayerA);
+    }
+
+    fn input(session: &mut GameSession, player: CellOwner, message: ClientMessage) {
+        session.process_player_input(player, message, &GameMessageFactory::new());
+    }
+
+    /// Owners of the cells, as "X", "O" or "." row by row.
+    fn cells(session: &GameSession) -> String {
+        session.board.cells().into_iter()
+            .map(|owner| match owner {
+                CellOwner::PlayerA => 'X',
+                CellOwner::PlayerB => 'O',
+                _ => '.',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn takeback_after_own_move_undoes_one_ply() {
+        let (mut session, ..) = game();
+        click(&mut session, CellOwner::PlayerA, "4");
+        assert_eq!(session.takeback_plies(CellOwner::PlayerA), 1);
+        input(&mut session, CellOwner::PlayerA, ClientMessage::Takeback);
+        input(&mut session, CellOwner::PlayerB, ClientMessage::AcceptTakeback);
+        assert_eq!(cells(&session), ".........");
+        assert!(session.moves.is_empty());
+        assert!(session.turn == CellOwner::PlayerA);
+        click(&mut session, CellOwner::PlayerA, "0");
+        assert_eq!(cells(&session), "X........");
+    }
+
+    #[test]
+    fn takeback_after_the_reply_undoes_two_plies() {
+        let (mut session, ..) = game();
+        for (player, cell) in [(CellOwner::PlayerA, "4"), (CellOwner::PlayerB, "0"), (CellOwner::PlayerA, "8")] {
+            click(&mut session, player, cell);
+        }
+        // O asks with X to move: the reply of X goes too
+        assert_eq!(session.takeback_plies(CellOwner::PlayerB), 2);
+        input(&mut session, CellOwner::PlayerB, ClientMessage::Takeback);
+        input(&mut session, CellOwner::PlayerA, ClientMessage::AcceptTakeback);
+        assert_eq!(cells(&session), "....X....");
+        assert_eq!(session.moves.len(), 1);
+        assert!(session.turn == CellOwner::PlayerB);
+        assert!(session.takeback_request.is_none());
+    }
+
+    #[test]
+    fn takeback_without_own_move_is_refused() {
+        let (mut session, mut receiver_a, mut receiver_b) = game();
+        received(&mut receiver_a);
+        received(&mut receiver_b);
+        input(&mut session, CellOwner::PlayerA, ClientMessage::Takeback);
+        assert_eq!(received(&mut receiver_a), vec![ServerMessage::Error(MoveError::NothingToTakeBack.to_string())]);
+        // O has not moved yet either
+        click(&mut session, CellOwner::PlayerA, "4");
+        assert_eq!(session.takeback_plies(CellOwner::PlayerB), 0);
+        assert!(session.takeback_request.is_none());
+        assert!(received(&mut receiver_b).iter().all(|message| !matches!(message, ServerMessage::Takeback(_))));
+    }
+
+    #[test]
+    fn declined_takeback_keeps_the_board() {
+        let (mut session, ..) = game();
+        click(&mut session, CellOwner::PlayerA, "4");
+        input(&mut session, CellOwner::PlayerA, ClientMessage::Takeback);
+        input(&mut session, CellOwner::PlayerB, ClientMessage::DeclineTakeback);
+        assert_eq!(cells(&session), "....X....");
+        assert!(session.turn == CellOwner::PlayerB);
+        // only the opponent of the requester may answer
+        input(&mut session, CellOwner::PlayerA, ClientMessage::Takeback);
+        input(&mut session, CellOwner::PlayerA, ClientMessage::AcceptTakeback);
+        assert_eq!(cells(&session), "....X....");
+    }
+
+    #[test]
+    fn resume_after_the_end_repeats_the_result() {
+        let (mut session, mut receiver_a, _receiver_b) = game();
+        click(&mut session, CellOwner::PlayerA, "4");
+        input(&mut session, CellOwner::PlayerB, ClientMessage::Resign);
+        input(&mut session, CellOwner::PlayerA, ClientMessage::Rematch);
+        received(&mut receiver_a);
+        let (sender_b, mut receiver_b) = unbounded();
+        session.resume(CellOwner::PlayerB, Arc::new(sender_b), &GameMessageFactory::new());
+        let factory = GameMessageFactory::new();
+        let messages = received(&mut receiver_b);
+        assert!(matches!(messages[0], ServerMessage::Board(_)));
+        assert!(matches!(messages[1], ServerMessage::State(_)));
+        assert_eq!(messages[2..], [
+            factory.get_default(GameMessageFactory::RESIGNED_MESSAGE).clone(),
+            factory.get_default(GameMessageFactory::REMATCH_OFFER_MESSAGE).clone(),
+        ]);
+        // nobody is to move, so the opponent hears nothing
+        assert!(received(&mut receiver_a).is_empty());
+    }
+}
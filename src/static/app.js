@@ -4,23 +4,99 @@ var figure = "";
 var gameState = "NO_GAME";
 var standardBackgroundColor = "#aa88b9";
 var endFlag = false;
+var pendingRoom = codeFromPath("room");
+var pendingSpectate = codeFromPath("watch");
+var spectating = false;
+var resumeToken = null;
+var reconnectAttempts = 0;
+var guestsAllowed = true;
+var loggedIn = false;
+const MAX_RECONNECT_ATTEMPTS = 10;
+const RECONNECT_DELAY = 2000;
+const PROTOCOL_VERSION = 1;
 
-function initialize() {
-    if (gameState === "IN_GAME") return;
+$(document).ready(() => {
+    loadAccount();
+    if (pendingRoom) $("h2").html("Tap here to join your friend's game!");
+    if (pendingSpectate) $("h2").html("Tap here to watch the game!");
+    $("#chat-input").on("keydown", event => {
+        if (event.key === "Enter") sendChat($("#chat-input").val());
+    });
+});
+
+// frames may be lost while the page sleeps in the background: redraw from a snapshot
+document.addEventListener("visibilitychange", () => {
+    if (document.visibilityState === "visible" && socketConnection && gameState !== "NO_GAME") {
+        socketConnection.next(createMessage("", "GET_STATE"));
+    }
+});
 
-    if (gameState === "NO_GAME") {
-        initializeCells();
-    } else if (gameState === "END_GAME") {
-        resetCells();
+function codeFromPath(prefix) {
+    const match = window.location.pathname.match(new RegExp(`^/${prefix}/([A-Za-z0-9]+)$`));
+    return match ? match[1] : null;
+}
+
+function initialize(opponent, level) {
+    if (gameState === "IN_GAME") return;
+    if (gameState === "RESULT") {
+        if (!spectating) socketConnection.next(createMessage("", "REMATCH"));
+        return;
+    }
+    if (!loggedIn && !guestsAllowed) {
+        $("h2").text("Log in or register to play");
+        return;
     }
+    if (!opponent && pendingRoom) opponent = "room";
+    if (!opponent && pendingSpectate) opponent = "spectate";
+    spectating = opponent === "spectate";
+
+    $("#room-link").html("");
+    $("#score").html("");
+    $("#chat-log").html("");
+    $("#chat").hide();
+    $("#timer").html("");
+    $("#new-opponent").hide();
     $("h2").css("background", standardBackgroundColor);
     gameState = "IN_GAME";
     endFlag = false;
-    connectSocket();
+    connectSocket(opponent, level);
 }
 
-function connectSocket() {
-    socketConnection = webSocket('SOCKET_HOST');
+function connectSocket(opponent, level) {
+    const variant = $("#variant").val();
+    let query;
+    if (variant === "ultimate") {
+        query = "?variant=ultimate";
+    } else {
+        const [width, height, k] = variant.split(",");
+        query = `?width=${width}&height=${height}&k=${k}`;
+    }
+    const clock = $("#clock").val();
+    if (clock) {
+        const [kind, seconds] = clock.split(",");
+        query += `&clock=${kind}&seconds=${seconds}`;
+    }
+    if (opponent === "computer") {
+        query += `&opponent=computer&level=${level}`;
+    } else if (opponent === "friend") {
+        query += "&room=new";
+    } else if (opponent === "room") {
+        query += `&room=${pendingRoom}`;
+        pendingRoom = null;
+    } else if (opponent === "spectate") {
+        query += `&spectate=${pendingSpectate}`;
+        pendingSpectate = null;
+    }
+    openSocket(query);
+}
+
+function reconnectSocket() {
+    reconnectAttempts++;
+    openSocket(`?resume=${resumeToken}`);
+}
+
+function openSocket(query) {
+    socketConnection = webSocket('SOCKET_HOST' + query);
     socketConnection.subscribe({
         next: msg => handleNext(msg), // Called whenever there is a message from the server.
         error: err => handleError(err), // Called if at any point WebSocket API signals some kind of error.
@@ -28,36 +104,200 @@ function connectSocket() {
     });
 }
 
+function playNewOpponent() {
+    endFlag = true;
+    resumeToken = null;
+    socketConnection.complete();
+    gameState = "END_GAME";
+    initialize();
+}
+
+function showLiveGames() {
+    $.getJSON("/games", games => {
+        const links = games.map(game => `<a href="/watch/${game.id}">Game ${game.id} (${game.variant})</a>`);
+        $("#live-games").html(links.length ? links.join(" | ") : "No game is being played right now");
+    });
+}
+
+function loadAccount() {
+    $.getJSON("/account", status => {
+        guestsAllowed = status.guests_allowed;
+        showAccount(status.account);
+    });
+}
+
+function showAccount(account) {
+    loggedIn = account !== null;
+    $("#account-name").text(loggedIn ? `Playing as ${account.name} (rating ${Math.round(account.rating)})` : "");
+    $("#logout").toggle(loggedIn);
+    $("#login-form").toggle(!loggedIn);
+}
+
+function sendCredentials(action) {
+    $.ajax({
+        url: `/account/${action}`,
+        method: "POST",
+        contentType: "application/json",
+        data: JSON.stringify({ name: $("#account-username").val(), password: $("#account-password").val() }),
+        dataType: "json",
+    }).done(account => {
+        $("#account-password").val("");
+        showAccount(account);
+    }).fail(xhr => $("#account-name").text(xhr.responseJSON ? xhr.responseJSON.error : "Could not reach the server"));
+}
+
+function logout() {
+    $.post("/account/logout", () => showAccount(null));
+}
+
 function clickImageHandler(clicked_id) {
     console.log("Clicked " + clicked_id)
+    if (spectating) return;
     socketConnection.next(createMessage(clicked_id, "CLIENT_CLICK"));
 }
 
 function createMessage(text, type) {
-    return { "text": text, "type": type };
+    return { "v": PROTOCOL_VERSION, "text": text, "type": type };
 }
 
 function handleNext(msg) {
     console.log(msg);
-    if (msg.type === "FIGURE") {
+    if (msg.type === "BOARD") {
+        gameState = "IN_GAME";
+        $("#new-opponent").hide();
+        $("h2").css("background", standardBackgroundColor);
+        if (msg.text === "ultimate") {
+            buildUltimateCells();
+        } else {
+            const [width, height] = msg.text.split(",").map(Number);
+            buildCells(width, height);
+        }
+    } else if (msg.type === "ACTIVE") {
+        $(".small-board").removeClass("active-board");
+        msg.text.split(",").forEach(board => $(`#board-${board}`).addClass("active-board"));
+    } else if (msg.type === "ROOM") {
+        const link = `${window.location.origin}/room/${msg.text}`;
+        $("#room-link").html(`Send this link to your friend: <a href="${link}">${link}</a>`);
+    } else if (msg.type === "TOKEN") {
+        resumeToken = msg.text;
+    } else if (msg.type === "FIGURE") {
         figure = msg.text;
     } else if (msg.type === "SHOW") {
-        $(`#${msg.text} .img-responsive`).attr("src", `images/${figure}.jpg`);
+        $(`#${msg.text} .img-responsive`).attr("src", `/images/${figure}.jpg`);
+        // a move drops any pending takeback or draw offer
+        $("#takeback-offer").hide();
+        $("#draw-offer").hide();
+    } else if (msg.type === "TAKEBACK") {
+        $("#takeback-offer").show();
+    } else if (msg.type === "DRAW_OFFER") {
+        $("#draw-offer").show();
+    } else if (msg.type === "CHAT") {
+        // the server escapes chat text
+        $("#chat-log").append($("<div>").html(msg.text));
+        $("#chat-log").scrollTop($("#chat-log")[0].scrollHeight);
     } else if (msg.type === "INFO") {
         console.log("Got an info!")
+        reconnectAttempts = 0;
+        $("h2").html(msg.text);
+    } else if (msg.type === "ERROR") {
+        console.log("Server error: " + msg.text);
+    } else if (msg.type === "RESULT") {
+        gameState = "RESULT";
+        showGameActions(false);
+        $("h2").html(msg.text.message);
+        $("h2").css("background", "darkseagreen");
+        if (!spectating) $("#new-opponent").show();
+    } else if (msg.type === "REMATCH") {
         $("h2").html(msg.text);
+    } else if (msg.type === "LINES") {
+        showWinningLines(msg.text);
+    } else if (msg.type === "SCORE") {
+        const [wins, losses, ties] = msg.text.split(",");
+        $("#score").html(`Score: ${wins} - ${losses} (ties: ${ties})`);
+    } else if (msg.type === "STATE") {
+        drawState(msg.text);
+    } else if (msg.type === "CLOCK") {
+        const [own, other] = msg.text.split(",").map(formatSeconds);
+        $("#timer").html(spectating ? `X ${own} | O ${other}` : `You ${own} | Opponent ${other}`);
     } else if (msg.type === "END") {
         console.log("Game end!")
+        showGameActions(false);
+        $("#chat").hide();
         endFlag = true;
+        resumeToken = null;
         $("h2").html(msg.text);
         $("h2").css("background", "darkseagreen");
         delayedEndGame();
     }
 }
 
+function drawState(state) {
+    showGameActions(state.figure && state.phase === "PLAYING");
+    if (state.figure && state.phase !== "LOBBY") $("#chat").show();
+    state.cells.forEach((cell, index) => {
+        const id = state.variant === "ultimate" ? `${Math.floor(index / 9)}-${index % 9}` : index;
+        $(`#${id} .img-responsive`).attr("src", `/images/${cell}.jpg`);
+    });
+    if (state.active_boards) {
+        $(".small-board").removeClass("active-board");
+        state.active_boards.forEach(board => $(`#board-${board}`).addClass("active-board"));
+    }
+    showWinningLines(state.winning_lines);
+}
+
+function showWinningLines(lines) {
+    $(".winning-cell").removeClass("winning-cell");
+    lines.forEach(line => line.forEach(cell => $(`#${cell}`).addClass("winning-cell")));
+}
+
+function showGameActions(playing) {
+    $("#game-actions").toggle(!!playing);
+    $("#takeback-offer").hide();
+    $("#draw-offer").hide();
+}
+
+function requestTakeback() {
+    socketConnection.next(createMessage("", "TAKEBACK"));
+}
+
+function answerTakeback(accepted) {
+    $("#takeback-offer").hide();
+    socketConnection.next(createMessage("", accepted ? "ACCEPT_TAKEBACK" : "DECLINE_TAKEBACK"));
+}
+
+function sendChat(text) {
+    if (!text.trim()) return;
+    socketConnection.next(createMessage(text, "CHAT"));
+    $("#chat-input").val("");
+}
+
+function offerDraw() {
+    socketConnection.next(createMessage("", "OFFER_DRAW"));
+}
+
+function answerDraw(accepted) {
+    $("#draw-offer").hide();
+    socketConnection.next(createMessage("", accepted ? "ACCEPT_DRAW" : "DECLINE_DRAW"));
+}
+
+function resign() {
+    if (confirm("Give this game up?")) socketConnection.next(createMessage("", "RESIGN"));
+}
+
+function formatSeconds(seconds) {
+    const minutes = Math.floor(seconds / 60);
+    return `${minutes}:${String(seconds % 60).padStart(2, "0")}`;
+}
+
 function handleError(err) {
     console.log(err);
     if (endFlag) return;
+    if (resumeToken && gameState !== "RESULT" && reconnectAttempts < MAX_RECONNECT_ATTEMPTS) {
+        $("h2").html("Connection lost, reconnecting...");
+        setTimeout(reconnectSocket, RECONNECT_DELAY);
+        return;
+    }
+    resumeToken = null;
     $("h2").html("Ops, connection lost<br>:(<br>Tap here to play again!");
     $("h2").css("background", "indianred");
     delayedEndGame();
@@ -73,21 +313,35 @@ function delayedEndGame() {
     }, 1400);
 }
 
-function initializeCells() {
+function buildCells(width, height) {
+    $(".board-grid").remove();
     $(".container").append(`
-        <div class="row mb-3" style="padding-right: 15px; padding-left: 15px;"></div>
+        <div class="board-grid mb-3" style="grid-template-columns: repeat(${width}, 1fr);"></div>
     `)
-    for (let i = 0; i < 9; i++) {
-        $(".row").append(`
-            <div id=${i} onClick="clickImageHandler(this.id)" class="col-xs-4 themed-grid-col">
-                <img src="images/empty-cell.jpg" class="img-responsive center-block">
+    for (let i = 0; i < width * height; i++) {
+        $(".board-grid").append(`
+            <div id=${i} onClick="clickImageHandler(this.id)" class="themed-grid-col">
+                <img src="/images/empty-cell.jpg" class="img-responsive center-block">
             </div>
         `)
     }
 }
 
-function resetCells() {
-    for (let i = 0; i < 9; i++) {
-        $(`#${i} .img-responsive`).attr("src", `images/empty-cell.jpg`);
+function buildUltimateCells() {
+    $(".board-grid").remove();
+    $(".container").append(`
+        <div class="board-grid mb-3" style="grid-template-columns: repeat(3, 1fr);"></div>
+    `)
+    for (let board = 0; board < 9; board++) {
+        $(".board-grid").append(`
+            <div id="board-${board}" class="small-board"></div>
+        `)
+        for (let cell = 0; cell < 9; cell++) {
+            $(`#board-${board}`).append(`
+                <div id="${board}-${cell}" onClick="clickImageHandler(this.id)" class="themed-grid-col">
+                    <img src="/images/empty-cell.jpg" class="img-responsive center-block">
+                </div>
+            `)
+        }
     }
 }
@@ -0,0 +1,39 @@
+$(document).ready(() => {
+    $.getJSON("/players/top", players => {
+        $("#empty-leaderboard").toggle(!players.length);
+        // player names are free text: never inject them as html
+        players.forEach(player => $("#leaderboard tbody").append($("<tr>").append(
+            $("<td>").text(player.rank),
+            $("<td>").append($("<a>").text(player.name).css("cursor", "pointer").click(() => showStats(player.name))),
+            $("<td>").text(Math.round(player.rating)),
+            $("<td>").text(player.rated_games)
+        )));
+    });
+});
+
+function showStats(name) {
+    $.getJSON(`/players/${encodeURIComponent(name)}/stats`, stats => {
+        const streak = stats.current_streak;
+        const opening = stats.favourite_opening;
+        const rows = [
+            ["Games", stats.games],
+            ["Wins / losses / ties", `${stats.wins} / ${stats.losses} / ${stats.ties}`],
+            ["Current streak", streak ? `${streak.games} ${streakName(streak)}` : "-"],
+            ["Longest winning streak", stats.longest_win_streak],
+            ["Average moves per game", stats.average_moves === null ? "-" : stats.average_moves.toFixed(1)],
+            ["Average game length", stats.average_seconds === null ? "-" : `${Math.round(stats.average_seconds)} seconds`],
+            ["Favourite opening", opening ? `${opening.square || opening.cell} on ${opening.variant} (${opening.games} games)` : "-"],
+        ];
+        $("#stats-name").text(`${stats.name}, rated ${Math.round(stats.rating)}`);
+        $("#player-stats tbody").empty().append(rows.map(([label, value]) =>
+            $("<tr>").append($("<th>").text(label), $("<td>").text(value))));
+        $("#player-stats").show();
+    });
+}
+
+function streakName(streak) {
+    const plural = streak.games > 1;
+    if (streak.result === "WIN") return plural ? "wins" : "win";
+    if (streak.result === "LOSS") return plural ? "losses" : "loss";
+    return plural ? "ties" : "tie";
+}
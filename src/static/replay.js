@@ -0,0 +1,62 @@
+const replayId = codeFromPath("replay");
+var replay = null;
+var ply = 0;
+
+$(document).ready(() => {
+    // cells of the replayed game are not clickable
+    spectating = true;
+    if (replayId) {
+        loadReplay(replayId);
+    } else {
+        showRecentGames();
+    }
+});
+
+function showRecentGames() {
+    $.getJSON("/games/recent", games => {
+        if (!games.length) {
+            $("#recent-games").text("No game was played to the end yet");
+            return;
+        }
+        // player names are free text: never inject them as html
+        games.forEach(game => $("#recent-games").append(
+            $("<a>").attr("href", `/replay/${game.id}`)
+                .text(`Game ${game.id}: ${game.player_x} vs ${game.player_o} (${game.variant})`),
+            "<br>"
+        ));
+    });
+}
+
+function loadReplay(id) {
+    $.getJSON(`/games/${id}/replay`, game => {
+        replay = game;
+        if (game.variant === "ultimate") {
+            buildUltimateCells();
+        } else {
+            const [width, height] = game.variant.split(",").map(Number);
+            buildCells(width, height);
+        }
+        $("#players").text(`X: ${game.player_x} | O: ${game.player_o}`);
+        $("#notation").attr("href", `/games/${id}/notation`);
+        $("#replay-controls").show();
+        showPly(0);
+    }).fail(() => $("h2").text("This game does not exist"));
+}
+
+function showPly(target) {
+    ply = Math.max(0, Math.min(target, replay.moves.length));
+    $(".board-grid .img-responsive").attr("src", "/images/empty-cell.jpg");
+    replay.moves.slice(0, ply).forEach(played =>
+        $(`#${played.cell} .img-responsive`).attr("src", `/images/${played.player.toLowerCase()}-cell.jpg`));
+    $("h2").text(ply === replay.moves.length ? resultText(replay) : `Move ${ply} of ${replay.moves.length}`);
+    showWinningLines(ply === replay.moves.length ? replay.winning_lines : []);
+}
+
+function resultText(game) {
+    if (game.result === "AGREED_DRAW") return "Draw agreed!";
+    if (!game.winner) return "Tie!";
+    if (game.result === "TIMEOUT") return `${game.winner} won on time!`;
+    if (game.result === "WITHDRAWAL") return `${game.winner} won, the opponent left!`;
+    if (game.result === "RESIGNATION") return `${game.winner} won, the opponent resigned!`;
+    return `${game.winner} won!`;
+}
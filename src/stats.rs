@@ -0,0 +1,213 @@
+use crate::board::notation::square_name;
+use crate::variant::Variant;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A stored game seen from one of its players.
+pub struct PlayedGame {
+    pub variant: String,
+    /// "X" or "O".
+    pub figure: String,
+    pub winner: Option<String>,
+    /// In the client encoding, separated by spaces.
+    pub moves: String,
+    pub started_at: u64,
+    pub ended_at: u64,
+}
+
+impl PlayedGame {
+    fn outcome(&self) -> &'static str {
+        match &self.winner {
+            None => "TIE",
+            Some(winner) if *winner == self.figure => "WIN",
+            Some(_) => "LOSS",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RankedPlayer {
+    pub rank: usize,
+    pub name: String,
+    pub rating: f64,
+    pub rated_games: u32,
+}
+
+#[derive(Serialize)]
+pub struct Streak {
+    /// "WIN", "LOSS" or "TIE".
+    pub result: &'static str,
+    pub games: u32,
+}
+
+/// Most played first move, only counted in games opened by the player.
+#[derive(Serialize)]
+pub struct Opening {
+    pub variant: String,
+    pub cell: String,
+    pub square: Option<String>,
+    pub games: u32,
+}
+
+#[derive(Serialize)]
+pub struct PlayerStats {
+    pub name: String,
+    pub rating: f64,
+    pub rated_games: u32,
+    pub games: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub ties: u32,
+    /// Results in a row up to the latest game.
+    pub current_streak: Option<Streak>,
+    pub longest_win_streak: u32,
+    pub average_moves: Option<f64>,
+    pub average_seconds: Option<f64>,
+    pub favourite_opening: Option<Opening>,
+}
+
+impl PlayerStats {
+    /// Statistics over the games of a player, oldest first.
+    pub fn new(name: String, rating: f64, rated_games: u32, games: &[PlayedGame]) -> PlayerStats {
+        let count = |outcome| games.iter().filter(|game| game.outcome() == outcome).count() as u32;
+        let current_streak = games.last().map(|latest| Streak {
+            result: latest.outcome(),
+            games: games.iter().rev().take_while(|game| game.outcome() == latest.outcome()).count() as u32,
+        });
+        let longest_win_streak = games.iter()
+            .scan(0, |streak, game| {
+                *streak = if game.outcome() == "WIN" { *streak + 1 } else { 0 };
+                Some(*streak)
+            })
+            .max()
+            .unwrap_or(0);
+        let average = |total: u64| (!games.is_empty()).then(|| total as f64 / games.len() as f64);
+        let mut openings: BTreeMap<(&str, &str), u32> = BTreeMap::new();
+        for game in games.iter().filter(|game| game.figure == "X") {
+            if let Some(first) = game.moves.split_whitespace().next() {
+                *openings.entry((&game.variant, first)).or_default() += 1;
+            }
+        }
+        // the first of equally played openings wins, so that the answer is stable
+        let favourite_opening = openings.into_iter()
+            .fold(None, |best: Option<((&str, &str), u32)>, (opening, played)| match best {
+                Some((_, most)) if most >= played => best,
+                _ => Some((opening, played)),
+            })
+            .map(|((variant, cell), played)| Opening {
+                variant: String::from(variant),
+                cell: String::from(cell),
+                square: Variant::parse(variant).and_then(|variant| square_name(variant, cell)),
+                games: played,
+            });
+        PlayerStats {
+            name,
+            rating,
+            rated_games,
+            games: games.len() as u32,
+            wins: count("WIN"),
+            losses: count("LOSS"),
+            ties: count("TIE"),
+            current_streak,
+            longest_win_streak,
+            average_moves: average(games.iter().map(|game| game.moves.split_whitespace().count() as u64).sum()),
+            average_seconds: average(games.iter().map(|game| game.ended_at.saturating_sub(game.started_at)).sum()),
+            favourite_opening,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A classic game of ten seconds, figure and winner being "X" or "O".
+    fn game(figure: &str, winner: Option<&str>, moves: &str) -> PlayedGame {
+        PlayedGame {
+            variant: String::from("3,3,3"),
+            figure: String::from(figure),
+            winner: winner.map(String::from),
+            moves: String::from(moves),
+            started_at: 100,
+            ended_at: 110,
+        }
+    }
+
+    fn stats(games: &[PlayedGame]) -> PlayerStats {
+        PlayerStats::new(String::from("alice"), 1500.0, games.len() as u32, games)
+    }
+
+    #[test]
+    fn results_are_counted_from_the_player_side() {
+        let stats = stats(&[
+            game("X", Some("X"), "4 0 8 2 6 3 1"),
+            game("O", Some("O"), "0 4 1 2 3 6"),
+            game("O", Some("X"), "0 3 1 4 2"),
+            game("X", None, "4 0 8 2 1 7 6 3 5"),
+        ]);
+        assert_eq!((stats.games, stats.wins, stats.losses, stats.ties), (4, 2, 1, 1));
+        assert_eq!(stats.average_moves, Some(6.75));
+        assert_eq!(stats.average_seconds, Some(10.0));
+    }
+
+    #[test]
+    fn streaks_run_up_to_the_latest_game() {
+        let stats = stats(&[
+            game("X", Some("X"), "4"),
+            game("X", Some("X"), "4"),
+            game("X", Some("X"), "4"),
+            game("O", Some("X"), "4"),
+            game("O", Some("O"), "4"),
+            game("X", Some("X"), "4"),
+        ]);
+        let streak = stats.current_streak.unwrap();
+        assert_eq!((streak.result, streak.games), ("WIN", 2));
+        assert_eq!(stats.longest_win_streak, 3);
+    }
+
+    #[test]
+    fn tie_breaks_a_win_streak() {
+        let stats = stats(&[
+            game("X", Some("X"), "4"),
+            game("X", Some("X"), "4"),
+            game("X", None, "4"),
+            game("X", Some("X"), "4"),
+            game("X", None, "4"),
+            game("O", None, "4"),
+        ]);
+        let streak = stats.current_streak.unwrap();
+        assert_eq!((streak.result, streak.games), ("TIE", 2));
+        assert_eq!(stats.longest_win_streak, 2);
+    }
+
+    #[test]
+    fn favourite_opening_only_counts_games_as_x() {
+        let stats = stats(&[
+            game("X", None, "0 4"),
+            game("X", None, "4 0"),
+            game("X", None, "4 8"),
+            // openings of the opponent do not count
+            game("O", None, "0 4"),
+            game("O", None, "0 4"),
+        ]);
+        let opening = stats.favourite_opening.unwrap();
+        assert_eq!((opening.cell.as_str(), opening.square.as_deref(), opening.games), ("4", Some("b2"), 2));
+    }
+
+    #[test]
+    fn equally_played_openings_keep_the_first() {
+        let opening = stats(&[game("X", None, "8"), game("X", None, "0")]).favourite_opening.unwrap();
+        assert_eq!(opening.cell, "0");
+    }
+
+    #[test]
+    fn empty_history_has_no_averages_nor_streaks() {
+        let stats = stats(&[]);
+        assert_eq!((stats.games, stats.wins, stats.losses, stats.ties), (0, 0, 0, 0));
+        assert!(stats.current_streak.is_none());
+        assert_eq!(stats.longest_win_streak, 0);
+        assert_eq!(stats.average_moves, None);
+        assert_eq!(stats.average_seconds, None);
+        assert!(stats.favourite_opening.is_none());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::board::{classic_lines, line_owner, lines_within, Bitmask, BoardSize, CellOwner, GameBoard, Outcome};
+
+/// Nine classic boards inside a big one: the cell played on a small board
+/// decides on which small board the opponent has to play next.
+#[derive(Clone)]
+pub struct UltimateBoard {
+    boards: Vec<GameBoard>,
+    next_board: Option<usize>,
+    /// Cells played, in order, to find where the previous move sent to.
+    played: Vec<(usize, usize)>,
+}
+
+impl UltimateBoard {
+    const SIDE: usize = 9;
+
+    pub fn new() -> UltimateBoard {
+        UltimateBoard {
+            boards: vec![GameBoard::new(BoardSize::CLASSIC); Self::SIDE],
+            next_board: None,
+            played: Vec::new(),
+        }
+    }
+
+    /// Moves are encoded as "board-cell", both indexes going from 0 to 8.
+    pub fn parse_move(text: &str) -> Option<(usize, usize)> {
+        let (board, cell) = text.split_once('-')?;
+        Some((board.parse().ok()?, cell.parse().ok()?))
+    }
+
+    pub fn format_move((board, cell): (usize, usize)) -> String {
+        format!("{}-{}", board, cell)
+    }
+
+    pub fn update_cell(&mut self, (board, cell): (usize, usize), owner: CellOwner) -> bool {
+        if board >= Self::SIDE || cell >= Self::SIDE || !self.is_playable(board)
+            || !self.boards[board].update_cell(cell, owner) {
+            return false;
+        }
+        self.played.push((board, cell));
+        self.next_board = self.board_sent_to(cell);
+        true
+    }
+
+    /// Clears the cell played last, false if the position is not that one.
+    pub fn undo_move(&mut self, (board, cell): (usize, usize)) -> bool {
+        if self.played.last() != Some(&(board, cell)) {
+            return false;
+        }
+        self.played.pop();
+        self.boards[board].undo_move(cell);
+        self.next_board = self.played.last()
+            .and_then(|&(_, previous_cell)| self.board_sent_to(previous_cell));
+        true
+    }
+
+    pub fn check_winner(&self) -> CellOwner {
+        let (won_a, won_b, decided) = self.big_board();
+        match line_owner(classic_lines(), &won_a, &won_b) {
+            CellOwner::None if decided == Bitmask::first(Self::SIDE) => CellOwner::Tie,
+            owner => owner,
+        }
+    }
+
+    /// The winner and, for every line of small boards they won, the cells
+    /// of the lines that won those boards.
+    pub fn check_outcome(&self) -> Outcome<(usize, usize)> {
+        let winner = self.check_winner();
+        let (won_a, won_b, _) = self.big_board();
+        let won = match winner {
+            CellOwner::PlayerA => won_a,
+            CellOwner::PlayerB => won_b,
+            _ => Bitmask::default(),
+        };
+        let lines = lines_within(classic_lines(), &won).into_iter()
+            .map(|boards| {
+                let mut cells: Vec<(usize, usize)> = boards.into_iter()
+                    .flat_map(|board| self.boards[board].completed_lines(winner).into_iter()
+                        .flatten()
+                        .map(move |cell| (board, cell)))
+                    .collect();
+                // a small board may be won by crossing lines
+                cells.sort_unstable();
+                cells.dedup();
+                cells
+            })
+            .collect();
+        Outcome { winner, lines }
+    }
+
+    pub fn playable_boards(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..Self::SIDE).filter(|&board| self.is_playable(board))
+    }
+
+    pub fn legal_moves(&self) -> Vec<(usize, usize)> {
+        self.playable_boards()
+            .flat_map(|board| self.boards[board].legal_moves().map(move |cell| (board, cell)))
+            .collect()
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = CellOwner> + '_ {
+        self.boards.iter().flat_map(|board| (0..Self::SIDE).map(move |cell| board.owner(cell)))
+    }
+
+    pub fn cell_count(&self) -> usize {
+        Self::SIDE * Self::SIDE
+    }
+
+    /// Where a move on this cell sends the opponent: a decided board cannot be
+    /// sent to, the opponent may then choose freely.
+    fn board_sent_to(&self, cell: usize) -> Option<usize> {
+        (self.boards[cell].check_winner() == CellOwner::None).then_some(cell)
+    }
+
+    /// The small boards won by each player, then those decided either way.
+    fn big_board(&self) -> (Bitmask, Bitmask, Bitmask) {
+        let (mut won_a, mut won_b, mut decided) = (Bitmask::default(), Bitmask::default(), Bitmask::default());
+        for (index, board) in self.boards.iter().enumerate() {
+            match board.check_winner() {
+                CellOwner::PlayerA => won_a.insert(index),
+                CellOwner::PlayerB => won_b.insert(index),
+                CellOwner::Tie => (),
+                CellOwner::None => continue,
+            }
+            decided.insert(index);
+        }
+        (won_a, won_b, decided)
+    }
+
+    fn is_playable(&self, board: usize) -> bool {
+        (self.next_board.is_none() || self.next_board == Some(board))
+            && self.boards[board].check_winner() == CellOwner::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(board: &mut UltimateBoard, moves: &[(usize, usize)]) {
+        let mut player = CellOwner::PlayerA;
+        for &position in moves {
+            assert!(board.update_cell(position, player), "move {:?} refused", position);
+            player = player.opponent();
+        }
+    }
+
+    #[test]
+    fn cell_played_sends_to_its_board() {
+        let mut board = UltimateBoard::new();
+        assert_eq!(board.playable_boards().count(), 9);
+        play(&mut board, &[(0, 4)]);
+        assert_eq!(board.playable_boards().collect::<Vec<_>>(), vec![4]);
+        assert!(!board.update_cell((0, 0), CellOwner::PlayerB));
+        play(&mut board, &[(4, 0)]);
+        assert_eq!(board.next_board, Some(0));
+    }
+
+    #[test]
+    fn decided_board_leaves_a_free_choice() {
+        let mut board = UltimateBoard::new();
+        // X takes the top row of board 0, the last move sending back to it
+        play(&mut board, &[(0, 1), (1, 0), (0, 2), (2, 0), (0, 0)]);
+        assert!(board.boards[0].check_winner() == CellOwner::PlayerA);
+        assert_eq!(board.next_board, None);
+        assert_eq!(board.playable_boards().collect::<Vec<_>>(), (1..9).collect::<Vec<_>>());
+        assert!(!board.update_cell((0, 5), CellOwner::PlayerB));
+        assert!(board.update_cell((7, 7), CellOwner::PlayerB));
+    }
+
+    #[test]
+    fn undo_move_restores_next_board() {
+        let mut board = UltimateBoard::new();
+        play(&mut board, &[(0, 1), (1, 0), (0, 2), (2, 0), (0, 0)]);
+        // only the last move can be taken back
+        assert!(!board.undo_move((2, 0)));
+        assert!(board.undo_move((0, 0)));
+        assert_eq!(board.next_board, Some(0));
+        assert!(board.boards[0].check_winner() == CellOwner::None);
+        assert!(board.undo_move((2, 0)));
+        assert_eq!(board.next_board, Some(2));
+        while let Some(&position) = board.played.last() {
+            assert!(board.undo_move(position));
+        }
+        assert_eq!(board.next_board, None);
+        assert_eq!(board.legal_moves().len(), 81);
+    }
+
+    #[test]
+    fn big_board_line_wins() {
+        let mut board = UltimateBoard::new();
+        for small in 0..3 {
+            for cell in [0, 4, 8] {
+                board.boards[small].update_cell(cell, CellOwner::PlayerB);
+            }
+        }
+        let outcome = board.check_outcome();
+        assert!(outcome.winner == CellOwner::PlayerB);
+        let expected: Vec<(usize, usize)> = (0..3)
+            .flat_map(|small| [0, 4, 8].map(|cell| (small, cell)))
+            .collect();
+        assert_eq!(outcome.lines, vec![expected]);
+    }
+
+    #[test]
+    fn decided_boards_without_line_are_a_tie() {
+        let mut board = UltimateBoard::new();
+        for small in 0..9 {
+            let owner = if [0, 2, 3, 7, 8].contains(&small) { CellOwner::PlayerA } else { CellOwner::PlayerB };
+            for cell in 0..3 {
+                board.boards[small].update_cell(cell, owner);
+            }
+        }
+        // an open small board keeps the big board open, a tied one does not
+        board.boards[4] = GameBoard::new(BoardSize::CLASSIC);
+        assert!(board.check_winner() == CellOwner::None);
+        for cell in 0..9 {
+            board.boards[4].update_cell(cell, CellOwner::Tie);
+        }
+        assert!(board.check_winner() == CellOwner::Tie);
+        assert!(board.check_outcome().lines.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::board::{BoardSize, CellOwner, GameBoard, Outcome};
+use crate::ultimate::UltimateBoard;
+use std::fmt;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Variant {
+    Standard(BoardSize),
+    Ultimate,
+}
+
+impl Variant {
+    /// Reads back the Display form, "w,h,k" or "ultimate".
+    pub fn parse(text: &str) -> Option<Variant> {
+        if text == "ultimate" {
+            return Some(Variant::Ultimate);
+        }
+        let mut dimensions = text.split(',').map(|dimension| dimension.trim().parse().ok());
+        match (dimensions.next(), dimensions.next(), dimensions.next(), dimensions.next()) {
+            (Some(Some(width)), Some(Some(height)), Some(Some(win_length)), None) =>
+                BoardSize::new(width, height, win_length).map(Variant::Standard),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variant::Standard(size) => write!(f, "{}", size),
+            Variant::Ultimate => write!(f, "ultimate"),
+        }
+    }
+}
+
+/// The board a session is played on, whatever its rule set.
+#[derive(Clone)]
+pub enum VariantBoard {
+    Standard(GameBoard),
+    Ultimate(UltimateBoard),
+}
+
+impl VariantBoard {
+    pub fn new(variant: Variant) -> VariantBoard {
+        match variant {
+            Variant::Standard(size) => VariantBoard::Standard(GameBoard::new(size)),
+            Variant::Ultimate => VariantBoard::Ultimate(UltimateBoard::new()),
+        }
+    }
+
+    pub fn variant(&self) -> Variant {
+        match self {
+            VariantBoard::Standard(board) => Variant::Standard(board.size()),
+            VariantBoard::Ultimate(_) => Variant::Ultimate,
+        }
+    }
+
+    /// Plays a move in the text encoding used by the client, answering it
+    /// written the canonical way: "+4" or "04" are played as "4".
+    pub fn play(&mut self, move_text: &str, owner: CellOwner) -> Option<String> {
+        match self {
+            VariantBoard::Standard(board) => move_text.parse().ok()
+                .filter(|&index| board.update_cell(index, owner))
+                .map(|index: usize| index.to_string()),
+            VariantBoard::Ultimate(board) => UltimateBoard::parse_move(move_text)
+                .filter(|&position| board.update_cell(position, owner))
+                .map(UltimateBoard::format_move),
+        }
+    }
+
+    /// Takes back the last move, given in the text encoding used by the
+    /// client: the session keeps the moves played, the board does not.
+    pub fn undo_move(&mut self, move_text: &str) -> bool {
+        match self {
+            VariantBoard::Standard(board) => move_text.parse().is_ok_and(|index| board.undo_move(index)),
+            VariantBoard::Ultimate(board) => UltimateBoard::parse_move(move_text)
+                .is_some_and(|position| board.undo_move(position)),
+        }
+    }
+
+    pub fn check_winner(&self) -> CellOwner {
+        match self {
+            VariantBoard::Standard(board) => board.check_winner(),
+            VariantBoard::Ultimate(board) => board.check_winner(),
+        }
+    }
+
+    /// The winner and the cells of the lines that decided the game, as moves
+    /// in the text encoding used by the client.
+    pub fn check_outcome(&self) -> Outcome<String> {
+        match self {
+            VariantBoard::Standard(board) => {
+                let Outcome { winner, lines } = board.check_outcome();
+                let lines = lines.into_iter()
+                    .map(|line| line.into_iter().map(|index| index.to_string()).collect())
+                    .collect();
+                Outcome { winner, lines }
+            }
+            VariantBoard::Ultimate(board) => {
+                let Outcome { winner, lines } = board.check_outcome();
+                let lines = lines.into_iter()
+                    .map(|line| line.into_iter().map(UltimateBoard::format_move).collect())
+                    .collect();
+                Outcome { winner, lines }
+            }
+        }
+    }
+
+    /// Owner of every cell, in the order of the client grid: row by row, or
+    /// small board by small board for ultimate games.
+    pub fn cells(&self) -> Vec<CellOwner> {
+        match self {
+            VariantBoard::Standard(board) => (0..board.size().cell_count())
+                .map(|index| board.owner(index))
+                .collect(),
+            VariantBoard::Ultimate(board) => board.cells().collect(),
+        }
+    }
+
+    /// Small boards the next move may go to, only meaningful for ultimate games.
+    pub fn playable_boards(&self) -> Option<Vec<usize>> {
+        match self {
+            VariantBoard::Standard(_) => None,
+            VariantBoard::Ultimate(board) => Some(board.playable_boards().collect()),
+        }
+    }
+}